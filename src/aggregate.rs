@@ -0,0 +1,370 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, hash::Hash, ops::{Add, Deref, Sub}, rc::Rc};
+
+use crate::{message_listeners::MessageListenersInterface, multi_set::MultiSetModifyMessage,
+    queryable_streaming_multi_map::{QuerableStreamingMultiMap, QuerableStreamingMultiMapGetter, StreamingHashMultiMapWithCount},
+    rc_borrow::RcBorrow};
+
+/// Maintains one aggregated value per key of `source`.
+///
+/// `update` gets the key, the current aggregate (None if the key has none) and the changes of
+///   the values of the key as `(value, diff)` pairs, and returns the new aggregate.  The output
+///   has a single value per key: a changed aggregate is sent as `Replace`, a new one as
+///   `InsertOne` and one that became None as `RemoveOne`.
+///
+/// Like `group_by`, the output starts with the current contents of the source.
+pub fn aggregate_by_key<'source, 'listener, K, V, Out, Source>(source: &Source,
+        mut update: impl FnMut(&K, Option<Out>, &[(V, i64)])->Option<Out> + 'listener)->
+        Rc<StreamingHashMultiMapWithCount<'listener, K, Out>>
+    where K: Eq+Hash+Clone+'static, V: Eq+Hash+Clone+'static, Out: Eq+Hash+Clone+'static,
+        Source: QuerableStreamingMultiMap<'source, 'listener, K, V> {
+    let r: Rc<StreamingHashMultiMapWithCount<'listener, K, Out>>=Rc::new(StreamingHashMultiMapWithCount::new());
+    r.listeners().depends_on(source.listeners());
+    let existing=source.weighted_items().into_iter().map(|(pair, count)| (pair, count as i64)).collect();
+    apply_changes(&r, existing, &mut update);
+    let weak=Rc::downgrade(&r);
+    r.listeners().hold(source.listen(move |message| {
+        if let Some(r)=weak.upgrade() {
+            apply_changes(&r, MultiSetModifyMessage::consolidate([message]), &mut update);
+        }
+    }));
+    r
+}
+
+/// Groups consolidated changes by key, in the order the keys first appeared.
+fn changes_by_key<K: Eq+Hash+Clone, V>(changes: Vec<((K, V), i64)>)->Vec<(K, Vec<(V, i64)>)> {
+    let mut keys=Vec::new();
+    let mut by_key: HashMap<K, Vec<(V, i64)>>=HashMap::new();
+    for ((key, value), diff) in changes {
+        by_key.entry(key.clone()).or_insert_with(|| {
+            keys.push(key);
+            Vec::new()
+        }).push((value, diff));
+    }
+    keys.into_iter().map(|key| {
+        let changes=by_key.remove(&key).unwrap();
+        (key, changes)
+    }).collect()
+}
+
+fn apply_changes<K: Eq+Hash+Clone+'static, V: Eq+Hash+Clone+'static, Out: Eq+Hash+Clone+'static>(
+        r: &StreamingHashMultiMapWithCount<'_, K, Out>, changes: Vec<((K, V), i64)>,
+        update: &mut impl FnMut(&K, Option<Out>, &[(V, i64)])->Option<Out>) {
+    r.transaction(|tx| for (key, changes) in changes_by_key(changes) {
+        let old=tx.get(&key).into_iter().next();
+        let new=update(&key, old.clone(), &changes);
+        if new==old {
+            continue;
+        }
+        match new {
+            Some(new)=>tx.set(key, new),
+            None=>tx.remove_key(&key)
+        }
+    });
+}
+
+/// The number of values of every key, see `QuerableStreamingMultiMap::count_by_key`.
+pub fn count_by_key<'source, 'listener, K, V, Source>(source: &Source)->Rc<StreamingHashMultiMapWithCount<'listener, K, u64>>
+    where K: Eq+Hash+Clone+'static, V: Eq+Hash+Clone+'static, Source: QuerableStreamingMultiMap<'source, 'listener, K, V> {
+    aggregate_by_key(source, |_, count, changes| {
+        let count=count.unwrap_or(0) as i64+changes.iter().map(|(_, diff)| diff).sum::<i64>();
+        (count>0).then_some(count as u64)
+    })
+}
+
+/// Reduces the values of every key with an invertible reducer, see
+///   `QuerableStreamingMultiMap::reduce_by_key`.
+pub fn reduce_by_key<'source, 'listener, K, V, Out, Source>(source: &Source, initial: Out,
+        add: impl Fn(Out, &V, u64)->Out + 'listener, retract: impl Fn(Out, &V, u64)->Out + 'listener)->
+        Rc<StreamingHashMultiMapWithCount<'listener, K, Out>>
+    where K: Eq+Hash+Clone+'static, V: Eq+Hash+Clone+'static, Out: Eq+Hash+Clone+'static,
+        Source: QuerableStreamingMultiMap<'source, 'listener, K, V> {
+    // The number of values per key, so that a key without values has no aggregate (instead
+    //   of the initial value).
+    let mut counts: HashMap<K, i64>=HashMap::new();
+    aggregate_by_key(source, move |key, out, changes| {
+        let mut out=out.unwrap_or_else(|| initial.clone());
+        let count=counts.entry(key.clone()).or_insert(0);
+        for (value, diff) in changes {
+            *count+=diff;
+            out=if *diff>0 { add(out, value, diff.unsigned_abs()) } else { retract(out, value, diff.unsigned_abs()) };
+        }
+        if *count>0 {
+            Some(out)
+        } else {
+            counts.remove(key);
+            None
+        }
+    })
+}
+
+/// The aggregates of `reduce_by_key_recompute`.  They are recomputed from the values of the
+///   source, so the source has to outlive them.
+pub struct RecomputedByKey<'listener, 'last_source, K: Eq+Hash+Clone+'static, Out: Eq+Hash+Clone+'static, SourceGetter> {
+    aggregates: Rc<StreamingHashMultiMapWithCount<'listener, K, Out>>,
+    _source_getter: RcBorrow<'last_source, SourceGetter>
+}
+
+impl<'listener, 'last_source, K: Eq+Hash+Clone+'static, Out: Eq+Hash+Clone+'static, SourceGetter> Deref
+        for RecomputedByKey<'listener, 'last_source, K, Out, SourceGetter> {
+    type Target=StreamingHashMultiMapWithCount<'listener, K, Out>;
+    fn deref(&self)->&Self::Target {
+        &self.aggregates
+    }
+}
+
+/// Reduces the values of every key by recomputing the aggregate from all values of the key,
+///   see `QuerableStreamingMultiMap::reduce_by_key_recompute`.
+pub fn reduce_by_key_recompute<'source, 'listener, 'last_source, K, V, Out, Source>(source: &'last_source Source,
+        f: impl Fn(&K, &HashSet<V>)->Out + 'listener)->RecomputedByKey<'listener, 'last_source, K, Out, Source::Getter>
+    where K: Eq+Hash+Clone+'static, V: Eq+Hash+Clone+'static, Out: Eq+Hash+Clone+'static,
+        Source: QuerableStreamingMultiMap<'source, 'listener, K, V> {
+    let source_getter=RcBorrow::new(source.getter());
+    let getter=source_getter.get();
+    let aggregates=aggregate_by_key(source, move |key, _, _| {
+        let values=getter.get(key);
+        (!values.is_empty()).then(|| f(key, &values))
+    });
+    RecomputedByKey { aggregates, _source_getter: source_getter }
+}
+
+/// `value` added up `times` times, by doubling it.
+fn repeat_add<V: Clone+Default+Add<Output=V>>(value: &V, mut times: u64)->V {
+    let (mut r, mut power)=(V::default(), value.clone());
+    while times>0 {
+        if times&1==1 {
+            r=r+power.clone();
+        }
+        times>>=1;
+        if times>0 {
+            power=power.clone()+power;
+        }
+    }
+    r
+}
+
+/// The sum of the values of every key, see `QuerableStreamingMultiMap::sum_by_key`.
+pub fn sum_by_key<'source, 'listener, K, V, Source>(source: &Source)->Rc<StreamingHashMultiMapWithCount<'listener, K, V>>
+    where K: Eq+Hash+Clone+'static, V: Eq+Hash+Clone+Default+Add<Output=V>+Sub<Output=V>+'static,
+        Source: QuerableStreamingMultiMap<'source, 'listener, K, V> {
+    reduce_by_key(source, V::default(), |sum, value, copies| sum+repeat_add(value, copies),
+        |sum, value, copies| sum-repeat_add(value, copies))
+}
+
+/// Keeps the values of every key in an ordered multiset, so that the aggregate chosen by
+///   `pick` stays correct when values are removed.
+fn ordered_by_key<'source, 'listener, K, V, Source>(source: &Source, pick: fn(&BTreeMap<V, u64>)->Option<&V>)->
+        Rc<StreamingHashMultiMapWithCount<'listener, K, V>>
+    where K: Eq+Hash+Clone+'static, V: Eq+Hash+Ord+Clone+'static, Source: QuerableStreamingMultiMap<'source, 'listener, K, V> {
+    let mut values_by_key: HashMap<K, BTreeMap<V, u64>>=HashMap::new();
+    aggregate_by_key(source, move |key, _, changes| {
+        let values=values_by_key.entry(key.clone()).or_default();
+        for (value, diff) in changes {
+            let count=values.entry(value.clone()).or_insert(0);
+            *count=(*count as i64+diff).max(0) as u64;
+            if *count==0 {
+                values.remove(value);
+            }
+        }
+        let r=pick(values).cloned();
+        if values.is_empty() {
+            values_by_key.remove(key);
+        }
+        r
+    })
+}
+
+/// The smallest value of every key, see `QuerableStreamingMultiMap::min_by_key`.
+pub fn min_by_key<'source, 'listener, K, V, Source>(source: &Source)->Rc<StreamingHashMultiMapWithCount<'listener, K, V>>
+    where K: Eq+Hash+Clone+'static, V: Eq+Hash+Ord+Clone+'static, Source: QuerableStreamingMultiMap<'source, 'listener, K, V> {
+    ordered_by_key(source, |values| values.keys().next())
+}
+
+/// The largest value of every key, see `QuerableStreamingMultiMap::max_by_key`.
+pub fn max_by_key<'source, 'listener, K, V, Source>(source: &Source)->Rc<StreamingHashMultiMapWithCount<'listener, K, V>>
+    where K: Eq+Hash+Clone+'static, V: Eq+Hash+Ord+Clone+'static, Source: QuerableStreamingMultiMap<'source, 'listener, K, V> {
+    ordered_by_key(source, |values| values.keys().next_back())
+}
+
+/// The values of a key of `top_k_by_key` by sort key, and the multiplicity of every value.
+type RankedValues<S, V>=(BTreeMap<S, Vec<V>>, HashMap<V, u64>);
+
+/// The `k` values with the largest sort key of every key, see
+///   `QuerableStreamingMultiMap::top_k_by_key`.
+pub fn top_k_by_key<'source, 'listener, K, V, S, Source>(source: &Source, k: usize, sort_key: impl Fn(&V)->S + 'listener)->
+        Rc<StreamingHashMultiMapWithCount<'listener, K, V>>
+    where K: Eq+Hash+Clone+'static, V: Eq+Hash+Clone+'static, S: Ord+'listener,
+        Source: QuerableStreamingMultiMap<'source, 'listener, K, V> {
+    let r: Rc<StreamingHashMultiMapWithCount<'listener, K, V>>=Rc::new(StreamingHashMultiMapWithCount::new());
+    r.listeners().depends_on(source.listeners());
+    // Values with the same sort key are kept in insertion order, so ties are broken the same
+    //   way every time and the window doesn't change when an unrelated value is removed.
+    //   A value is ranked once, however many copies of it the source has.
+    let mut values_by_key: HashMap<K, RankedValues<S, V>>=HashMap::new();
+    let mut update=move |r: &StreamingHashMultiMapWithCount<'listener, K, V>, changes: Vec<((K, V), i64)>| {
+        r.transaction(|tx| for (key, changes) in changes_by_key(changes) {
+            let (values, counts)=values_by_key.entry(key.clone()).or_default();
+            for (value, diff) in changes {
+                let old=counts.get(&value).copied().unwrap_or(0);
+                let count=(old as i64+diff).max(0) as u64;
+                let s=sort_key(&value);
+                if old==0 && count>0 {
+                    counts.insert(value.clone(), count);
+                    values.entry(s).or_default().push(value);
+                } else if count==0 {
+                    counts.remove(&value);
+                    if let Some(tied)=values.get_mut(&s) {
+                        tied.retain(|v| *v!=value);
+                        if tied.is_empty() {
+                            values.remove(&s);
+                        }
+                    }
+                } else {
+                    counts.insert(value, count);
+                }
+            }
+            let top: HashSet<V>=values.values().rev().flatten().take(k).cloned().collect();
+            if values.is_empty() {
+                values_by_key.remove(&key);
+            }
+            let old=tx.get(&key);
+            // A value that enters the top displaces one that left it, sent as a `Replace`.
+            let mut displaced=old.difference(&top).cloned();
+            let mut entered=top.difference(&old).cloned();
+            loop {
+                match (displaced.next(), entered.next()) {
+                    (Some(old), Some(new))=>tx.replace(key.clone(), old, new),
+                    (Some(old), None)=>{tx.remove(key.clone(), old);},
+                    (None, Some(new))=>tx.insert(key.clone(), new),
+                    (None, None)=>break
+                }
+            }
+        });
+    };
+    update(&r, source.weighted_items().into_iter().map(|(pair, count)| (pair, count as i64)).collect());
+    let weak=Rc::downgrade(&r);
+    r.listeners().hold(source.listen(move |message| {
+        if let Some(r)=weak.upgrade() {
+            update(&r, MultiSetModifyMessage::consolidate([message]));
+        }
+    }));
+    r
+}
+
+#[test]
+fn test_count_by_key() {
+    let follows = StreamingHashMultiMapWithCount::new();
+    follows.insert("alice", "bob");
+    let followers = follows.reversed();
+    let counts = followers.count_by_key();
+    let messages = Rc::new(std::cell::RefCell::new(Vec::new()));
+    let mclone = messages.clone();
+    let _subscription = counts.listen(move |message| mclone.borrow_mut().push(message));
+    follows.insert("carol", "bob");
+    follows.insert("carol", "bob");
+    follows.insert("bob", "alice");
+    follows.remove("alice", "bob");
+    follows.remove("bob", "alice");
+    // Every copy of a value is counted.
+    assert_eq!(counts.get_one(&"bob"), Some(2));
+    assert_eq!(*messages.borrow(), vec![
+        MultiSetModifyMessage::Replace { old: ("bob", 1), new: ("bob", 2) },
+        MultiSetModifyMessage::Replace { old: ("bob", 2), new: ("bob", 3) },
+        MultiSetModifyMessage::InsertOne(("alice", 1)),
+        MultiSetModifyMessage::Replace { old: ("bob", 3), new: ("bob", 2) },
+        MultiSetModifyMessage::RemoveOne(("alice", 1))]);
+    let names = StreamingHashMultiMapWithCount::new();
+    names.insert("bob", "Bob");
+    let joined_map = counts.join(&names);
+    assert_eq!(joined_map.get_one(&"bob"), Some((2, "Bob")));
+}
+
+#[test]
+fn test_sum_min_max_by_key() {
+    let scores = StreamingHashMultiMapWithCount::new();
+    scores.insert("alice", 3);
+    scores.insert("alice", 5);
+    let sums = scores.sum_by_key();
+    let mins = scores.min_by_key();
+    let maxs = scores.max_by_key();
+    scores.insert("alice", 4);
+    scores.insert("bob", 0);
+    assert_eq!((sums.get_one(&"alice"), mins.get_one(&"alice"), maxs.get_one(&"alice")), (Some(12), Some(3), Some(5)));
+    assert_eq!(sums.get_one(&"bob"), Some(0));
+    scores.remove("alice", 5);
+    scores.remove("alice", 3);
+    assert_eq!((sums.get_one(&"alice"), mins.get_one(&"alice"), maxs.get_one(&"alice")), (Some(4), Some(4), Some(4)));
+    scores.add("alice", 3, 7);
+    assert_eq!(sums.get_one(&"alice"), Some(25));
+    scores.add("alice", 3, -7);
+    scores.remove("alice", 4);
+    scores.remove("bob", 0);
+    assert_eq!((sums.items(), mins.items(), maxs.items()), (vec![], vec![], vec![]));
+}
+
+#[test]
+fn test_latest_tweet_time_per_user() {
+    use chrono::NaiveDate;
+    let time = |hour| NaiveDate::from_ymd_opt(2023, 1, 1).unwrap().and_hms_opt(hour, 0, 0).unwrap();
+    let tweets = StreamingHashMultiMapWithCount::new();
+    tweets.insert("alice", (time(9), "morning".to_string()));
+    tweets.insert("alice", (time(18), "evening".to_string()));
+    let latest = tweets.max_by_key();
+    assert_eq!(latest.get_one(&"alice"), Some((time(18), "evening".to_string())));
+    tweets.remove("alice", (time(18), "evening".to_string()));
+    assert_eq!(latest.get_one(&"alice"), Some((time(9), "morning".to_string())));
+}
+
+#[test]
+fn test_reduce_by_key() {
+    let ratings = StreamingHashMultiMapWithCount::new();
+    ratings.insert("movie", 4);
+    // (sum, count), the average is sum / count.
+    let averages = ratings.reduce_by_key((0, 0), |(sum, count), rating, copies| (sum + rating * copies, count + copies),
+        |(sum, count), rating, copies| (sum - rating * copies, count - copies));
+    let histograms = ratings.reduce_by_key_recompute(|_, values| {
+        let mut histogram = [0; 6];
+        for rating in values {
+            histogram[*rating as usize] += 1;
+        }
+        histogram
+    });
+    ratings.insert("movie", 2);
+    ratings.insert("movie", 5);
+    assert_eq!(averages.get_one(&"movie"), Some((11, 3)));
+    assert_eq!(histograms.get_one(&"movie"), Some([0, 0, 1, 0, 1, 1]));
+    ratings.remove("movie", 4);
+    assert_eq!(averages.get_one(&"movie"), Some((7, 2)));
+    assert_eq!(histograms.get_one(&"movie"), Some([0, 0, 1, 0, 0, 1]));
+    // A weighted change calls the reducer once.
+    ratings.add("movie", 3, 1_000_000);
+    assert_eq!(averages.get_one(&"movie"), Some((3_000_007, 1_000_002)));
+    assert_eq!(histograms.get_one(&"movie"), Some([0, 0, 1, 1, 0, 1]));
+    ratings.add("movie", 3, -1_000_000);
+    ratings.remove("movie", 2);
+    ratings.remove("movie", 5);
+    assert_eq!((averages.items(), histograms.items()), (vec![], vec![]));
+}
+
+#[test]
+fn test_top_k_by_key() {
+    let tweets = StreamingHashMultiMapWithCount::new();
+    tweets.insert("alice", (1, "first"));
+    tweets.insert("alice", (3, "third"));
+    tweets.insert("alice", (2, "second"));
+    let timeline = tweets.top_k_by_key(2, |(time, _)| *time);
+    assert_eq!(timeline.get(&"alice"), HashSet::from_iter(vec![(3, "third"), (2, "second")]));
+    let messages = Rc::new(std::cell::RefCell::new(Vec::new()));
+    let mclone = messages.clone();
+    let _subscription = timeline.listen(move |message| mclone.borrow_mut().push(message));
+    tweets.insert("alice", (0, "old"));
+    assert!(messages.borrow().is_empty());
+    tweets.insert("alice", (4, "fourth"));
+    tweets.remove("alice", (3, "third"));
+    assert_eq!(timeline.get(&"alice"), HashSet::from_iter(vec![(4, "fourth"), (2, "second")]));
+    tweets.remove("alice", (4, "fourth"));
+    // A displaced entry is sent as a replace by the one that took its place.
+    assert_eq!(*messages.borrow(), vec![
+        MultiSetModifyMessage::Replace { old: ("alice", (2, "second")), new: ("alice", (4, "fourth")) },
+        MultiSetModifyMessage::Replace { old: ("alice", (3, "third")), new: ("alice", (2, "second")) },
+        MultiSetModifyMessage::Replace { old: ("alice", (4, "fourth")), new: ("alice", (1, "first")) }]);
+}
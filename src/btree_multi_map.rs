@@ -0,0 +1,180 @@
+use std::{cell::RefCell, collections::{BTreeMap, HashMap, HashSet}, hash::Hash, ops::RangeBounds};
+
+use crate::{message_listeners::{MessageListeners, MessageListenersInterface}, multi_set::{MultiSetMessageListeners, MultiSetModifyMessage, Multiplicities},
+    queryable_streaming_multi_map::{QuerableStreamingMultiMap, QuerableStreamingMultiMapGetter}};
+#[cfg(test)]
+use crate::queryable_streaming_multi_map::StreamingHashMultiMapWithCount;
+
+type BTreeMultiMapData<K, V> = RefCell<BTreeMap<K, BTreeMap<V, u64>>>;
+
+/// An ordered version of `StreamingHashMultiMapWithCount`: keys and the values of every key
+///   are kept sorted, so ranges of keys can be queried and values are iterated in order.
+///
+/// Like the hash version, the listeners get every change of the multiplicity of a pair.
+pub struct StreamingBTreeMultiMapWithCount<'listener, K: Ord+Eq+Hash+Clone+'static, V: Ord+Eq+Hash+Clone+'static> {
+    listeners: MultiSetMessageListeners<'listener, (K, V)>,
+    data: BTreeMultiMapData<K, V>
+}
+
+impl<'a, K: Ord+Eq+Hash+Clone+'static, V: Ord+Eq+Hash+Clone+'static> Default for StreamingBTreeMultiMapWithCount<'a, K, V> {
+    fn default()->Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord, V: Ord> Multiplicities<(K, V)> for BTreeMap<K, BTreeMap<V, u64>> {
+    fn count(&self, (key, value): &(K, V))->u64 {
+        self.get(key).and_then(|values| values.get(value)).copied().unwrap_or(0)
+    }
+    fn set_count(&mut self, (key, value): (K, V), count: u64) {
+        if count>0 {
+            self.entry(key).or_default().insert(value, count);
+        } else if let Some(values)=self.get_mut(&key) {
+            values.remove(&value);
+            if values.is_empty() {
+                self.remove(&key);
+            }
+        }
+    }
+    fn is_empty(&self)->bool {
+        BTreeMap::is_empty(self)
+    }
+}
+
+impl<K: Ord+Eq+Hash+Clone+'static, V: Ord+Eq+Hash+Clone+'static> QuerableStreamingMultiMapGetter<K, V> for BTreeMultiMapData<K, V> {
+    fn get(&self, key: &K)->HashSet<V> {
+        match self.borrow().get(key) {
+            None => HashSet::new(),
+            Some(values) => values.keys().cloned().collect()
+        }
+    }
+    fn items(&self)->Vec<(K, V)> {
+        self.borrow().iter().flat_map(|(k, values)| values.keys().map(|v| (k.clone(), v.clone()))).collect()
+    }
+    fn counts(&self, key: &K)->HashMap<V, u64> {
+        self.borrow().get(key).map(|values| values.iter().map(|(v, count)| (v.clone(), *count)).collect()).unwrap_or_default()
+    }
+    fn weighted_items(&self)->Vec<((K, V), u64)> {
+        self.borrow().iter().flat_map(|(k, values)| values.iter().map(|(v, count)| ((k.clone(), v.clone()), *count))).collect()
+    }
+}
+
+impl<'listener, K: Ord+Eq+Hash+Clone+'static, V: Ord+Eq+Hash+Clone+'static>
+    QuerableStreamingMultiMap<'_, 'listener, K, V> for StreamingBTreeMultiMapWithCount<'listener, K, V> {
+    type Getter = BTreeMultiMapData<K, V>;
+    fn getter(&self)->&Self::Getter {
+        &self.data
+    }
+}
+
+impl<'a, K: Ord+Eq+Hash+Clone+'static, V: Ord+Eq+Hash+Clone+'static>
+    MessageListenersInterface<'a, MultiSetModifyMessage<(K, V)>> for StreamingBTreeMultiMapWithCount<'a, K, V> {
+    fn listeners(&self)->&MessageListeners<'a, MultiSetModifyMessage<(K, V)>> {
+        &self.listeners
+    }
+}
+
+impl<'a, K: Ord+Eq+Hash+Clone+'static, V: Ord+Eq+Hash+Clone+'static> StreamingBTreeMultiMapWithCount<'a, K, V> {
+    pub fn new()->Self {
+        Self {listeners: MultiSetMessageListeners::new(), data: RefCell::new(BTreeMap::new())}
+    }
+    pub fn insert(&self, key: K, value: V) {
+        self.apply(MultiSetModifyMessage::InsertOne((key, value)));
+    }
+    /// Removes one occurrence of the pair, returns false if it wasn't present.
+    pub fn remove(&self, key: K, value: V)->bool {
+        if self.count(&key, &value)==0 {
+            return false;
+        }
+        self.apply(MultiSetModifyMessage::RemoveOne((key, value)));
+        true
+    }
+    /// Changes the multiplicity of the pair by `diff`, without going below 0.
+    pub fn add(&self, key: K, value: V, diff: i64) {
+        self.apply(MultiSetModifyMessage::Delta((key, value), diff));
+    }
+    /// Replaces all values of the key with the value, see `StreamingHashMultiMapWithCount::set`.
+    pub fn set(&self, key: K, value: V) {
+        let old=self.values(&key);
+        let message=if old.len()==1 && old[0]!=value {
+            MultiSetModifyMessage::Replace { old: (key.clone(), old[0].clone()), new: (key, value) }
+        } else {
+            let mut messages: Vec<_>=old.into_iter().map(|v| MultiSetModifyMessage::RemoveOne((key.clone(), v))).collect();
+            messages.push(MultiSetModifyMessage::InsertOne((key, value)));
+            MultiSetModifyMessage::Batch(messages)
+        };
+        self.apply(message);
+    }
+    /// Removes every pair, sending a `Clear` message, see `MultiSetModifyMessage::remove_all`.
+    pub fn clear(&self) {
+        let data=std::mem::take(&mut *self.data.borrow_mut());
+        let removed=data.into_iter().flat_map(|(k, values)| values.into_iter().map(move |(v, count)| ((k.clone(), v), count))).collect();
+        if let Some(message)=MultiSetModifyMessage::remove_all(removed, MultiSetModifyMessage::Clear) {
+            self.listeners.send(message);
+        }
+    }
+    /// Applies a message to the map; the listeners get a single (batch) message with the
+    ///   changes of the multiplicities.  A `Clear` removes only the listed pairs, see
+    ///   `MultiSetModifyMessage::Clear`.
+    ///
+    /// The data is not borrowed while the listeners run.
+    pub fn apply(&self, message: MultiSetModifyMessage<(K, V)>) {
+        let message=message.apply_to(&mut *self.data.borrow_mut());
+        if let Some(message)=message {
+            self.listeners.send(message);
+        }
+    }
+    /// How many times the pair was inserted (and not removed).
+    pub fn count(&self, key: &K, value: &V)->u64 {
+        self.data.borrow().get(key).and_then(|values| values.get(value)).copied().unwrap_or(0)
+    }
+    /// The values of the key in order.
+    pub fn values(&self, key: &K)->Vec<V> {
+        self.data.borrow().get(key).map(|values| values.keys().cloned().collect()).unwrap_or_default()
+    }
+    /// The pairs whose key is in the range, ordered by key and then by value.
+    pub fn range(&self, range: impl RangeBounds<K>)->Vec<(K, V)> {
+        self.data.borrow().range(range).flat_map(|(k, values)| values.keys().map(|v| (k.clone(), v.clone()))).collect()
+    }
+    /// The keys in order.
+    pub fn keys(&self)->Vec<K> {
+        self.data.borrow().keys().cloned().collect()
+    }
+}
+
+#[test]
+fn test_btree_multi_map_range() {
+    let tweets = StreamingBTreeMultiMapWithCount::new();
+    tweets.insert(3, "c");
+    tweets.insert(1, "a");
+    tweets.insert(2, "b2");
+    tweets.insert(2, "b1");
+    tweets.insert(4, "d");
+    assert_eq!(tweets.range(2..4), vec![(2, "b1"), (2, "b2"), (3, "c")]);
+    assert_eq!(tweets.range(..=1), vec![(1, "a")]);
+    assert_eq!(tweets.values(&2), vec!["b1", "b2"]);
+    assert_eq!(tweets.keys(), vec![1, 2, 3, 4]);
+    assert!(tweets.remove(2, "b1"));
+    assert!(!tweets.remove(2, "b1"));
+    assert_eq!(tweets.items(), vec![(1, "a"), (2, "b2"), (3, "c"), (4, "d")]);
+    tweets.apply(MultiSetModifyMessage::Clear(vec![(1, "a"), (3, "c")]));
+    assert_eq!(tweets.items(), vec![(2, "b2"), (4, "d")]);
+}
+
+#[test]
+fn test_btree_multi_map_join() {
+    let tweets = StreamingBTreeMultiMapWithCount::new();
+    let names = StreamingHashMultiMapWithCount::new();
+    let joined_map = tweets.join(&names);
+    let grouped = joined_map.group_by(|user, (tweet, name)| (name, (user, tweet)));
+    let messages = std::rc::Rc::new(RefCell::new(Vec::new()));
+    let mclone = messages.clone();
+    let _subscription = tweets.listen(move |message| mclone.borrow_mut().push(message));
+    names.insert("alice", "Alice");
+    tweets.insert("alice", "hello");
+    tweets.set("alice", "hi");
+    assert_eq!(joined_map.get_one(&"alice"), Some(("hi", "Alice")));
+    assert_eq!(grouped.get_one(&"Alice"), Some(("alice", "hi")));
+    assert_eq!(*messages.borrow(), vec![MultiSetModifyMessage::InsertOne(("alice", "hello")),
+        MultiSetModifyMessage::Replace { old: ("alice", "hello"), new: ("alice", "hi") }]);
+}
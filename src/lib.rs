@@ -1,10 +1,24 @@
+
 pub mod rc_borrow;
+pub mod scheduler;
 pub mod message_listeners;
+pub mod sync_message_listeners;
+pub mod stream_bridge;
 
 pub mod multi_set;
 pub mod queryable_streaming_multi_map;
+pub mod sync_queryable_streaming_multi_map;
+pub mod btree_multi_map;
+pub mod aggregate;
+pub mod ordered_view;
+pub mod time_window;
+pub mod multi_join;
 pub use queryable_streaming_multi_map::{QuerableStreamingMultiMap, StreamingHashMultiMapWithCount};
-pub use multi_set::{MultiSetMessageListeners, MultiSetModifyMessage};
+pub use btree_multi_map::StreamingBTreeMultiMapWithCount;
+pub use ordered_view::{ListModifyMessage, OrderedView, WindowView};
+pub use time_window::Clock;
+pub use sync_queryable_streaming_multi_map::{SyncQuerableStreamingMultiMap, SyncStreamingHashMultiMapWithCount};
+pub use multi_set::{MultiSetMessageListeners, MultiSetModifyMessage, StreamingHashMultiSet, SyncMultiSetMessageListeners};
 pub mod twitter;
 
 
@@ -1,53 +1,479 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::{Cell, RefCell}, collections::VecDeque, error::Error, rc::{Rc, Weak}};
 
+use crate::{scheduler::Scheduler, stream_bridge::{ListenerStream, OverflowPolicy}};
+
+type Listener<'listener, M> = Box<dyn FnMut(M)->Result<(), Rc<dyn Error>> + 'listener>;
+type ErrorSink<'listener, M> = Box<dyn FnMut(ListenerError<M>) + 'listener>;
+
+struct Slot<'listener, M> {
+    generation: u64,
+    policy: ErrorPolicy,
+    listener: Option<Listener<'listener, M>>
+}
+
+/// What happens when a listener added with `try_listen` returns an error.
+///
+/// The error is reported to the error sink of the `MessageListeners` in every case.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// The listener stays subscribed and receives the next messages.
+    #[default]
+    Report,
+    /// The listener is removed, as if its subscription was cancelled.
+    DropListener,
+    /// The listener is called again with the same message, at most the given number of times.
+    ///   The error of the last attempt is reported if all of them fail.
+    ///
+    /// The retries happen immediately, without any backoff, so this is meant for listeners
+    ///   that can fix the cause of the error themselves (for example by reconnecting).
+    Retry(u32),
+    /// The message is not delivered to the listeners added after the failing one.
+    StopPropagation,
+}
+
+/// Identifies a listener of a `MessageListeners`, see `Subscription::id`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ListenerId {
+    index: usize,
+    generation: u64
+}
+
+/// A failure of a listener, reported to the error sink of its `MessageListeners`.
+#[derive(Clone, Debug)]
+pub struct ListenerError<M> {
+    pub listener: ListenerId,
+    pub message: M,
+    pub error: Rc<dyn Error>,
+    /// How many times the listener was called with the message.
+    pub attempts: u32
+}
+
+struct ListenersState<'listener, M> {
+    slots: RefCell<Vec<Slot<'listener, M>>>,
+    free: RefCell<Vec<usize>>,
+    pending: RefCell<VecDeque<M>>,
+    scheduled: Cell<bool>,
+    dispatching: Cell<bool>,
+    running: Cell<Option<usize>>,
+    running_cancelled: Cell<bool>,
+    error_sink: RefCell<Option<ErrorSink<'listener, M>>>,
+    errors: RefCell<VecDeque<ListenerError<M>>>
+}
+
+/// How many errors are kept while no error sink is set.
+const KEPT_ERRORS: usize=64;
+
+trait CancelListener {
+    fn cancel(&self, index: usize, generation: u64);
+}
+
+impl<'listener, M> CancelListener for ListenersState<'listener, M> {
+    fn cancel(&self, index: usize, generation: u64) {
+        if self.slots.borrow()[index].generation!=generation {
+            return;
+        }
+        if self.running.get()==Some(index) {
+            self.running_cancelled.set(true);
+        } else {
+            self.release(index);
+        }
+    }
+}
+
+impl<'listener, M> ListenersState<'listener, M> {
+    fn release(&self, index: usize) {
+        let listener = {
+            let mut slots=self.slots.borrow_mut();
+            slots[index].generation+=1;
+            slots[index].listener.take()
+        };
+        self.free.borrow_mut().push(index);
+        drop(listener);
+    }
+}
+
+/// A handle to a listener added with `MessageListeners::listen`.
+///
+/// The listener is removed when the subscription is dropped, unless `detach` is called, in
+///   which case it lives as long as the `MessageListeners` it was added to.
+#[must_use = "dropping a Subscription removes the listener immediately"]
+pub struct Subscription<'listener> {
+    state: Option<Weak<dyn CancelListener + 'listener>>,
+    index: usize,
+    generation: u64
+}
+
+impl<'listener> Subscription<'listener> {
+    /// The id that errors of this listener are reported with.
+    pub fn id(&self)->ListenerId {
+        ListenerId { index: self.index, generation: self.generation }
+    }
+
+    /// Keeps the listener subscribed for the lifetime of the `MessageListeners`.
+    pub fn detach(mut self) {
+        self.state=None;
+    }
+
+    /// Removes the listener, same as dropping the subscription.
+    pub fn cancel(self) {}
+}
+
+impl<'listener> Drop for Subscription<'listener> {
+    fn drop(&mut self) {
+        if let Some(state)=self.state.take().and_then(|state| state.upgrade()) {
+            state.cancel(self.index, self.generation);
+        }
+    }
+}
+
+/// A list of callbacks that receive every message sent to it.
+///
+/// Dispatch is re-entrant: a listener may call `listen`, `send` or drop a `Subscription` of the
+///   same `MessageListeners` while a message is being delivered.  The delivery order is:
+///
+/// - Messages are delivered one at a time in the order they were sent.  A message sent from
+///   a listener is queued and delivered after the current message reached every listener.
+/// - Listeners are called in the order they were added.  A listener added during dispatch
+///   only receives messages whose delivery starts after it was added.
+/// - A cancelled listener is not called again, even for the message currently being delivered.
+///
+/// Delivery goes through the `Scheduler` of the graph: a message sent while an update is
+///   propagating is delivered when all nodes with a lower rank are done, so a listener never
+///   runs while one of the sources of its node still has changes to deliver.
 pub struct MessageListeners<'listener, M> {
-    listeners: RefCell<Vec<Option<Box<dyn FnMut(M) + 'listener>>>>
+    state: Rc<ListenersState<'listener, M>>,
+    upstream: RefCell<Vec<Subscription<'listener>>>,
+    scheduler: Rc<Scheduler<'listener>>,
+    rank: Cell<u32>
+}
+
+impl<'listener, M:Clone+'static> Default for MessageListeners<'listener, M> {
+    fn default()->Self {
+        Self::new()
+    }
+}
+
+/// Resets the dispatch state even if a listener panics, so the `MessageListeners` stays usable.
+struct DispatchGuard<'a, 'listener, M>(&'a ListenersState<'listener, M>);
+
+impl<'a, 'listener, M> Drop for DispatchGuard<'a, 'listener, M> {
+    fn drop(&mut self) {
+        self.0.running.set(None);
+        self.0.running_cancelled.set(false);
+        self.0.dispatching.set(false);
+    }
 }
 
 impl<'listener, M:Clone+'static> MessageListeners<'listener, M> {
     pub fn new()->Self {
-        MessageListeners { listeners: RefCell::new(Vec::new()) }
+        MessageListeners {
+            state: Rc::new(ListenersState { slots: RefCell::new(Vec::new()), free: RefCell::new(Vec::new()),
+                pending: RefCell::new(VecDeque::new()), scheduled: Cell::new(false), dispatching: Cell::new(false),
+                running: Cell::new(None), running_cancelled: Cell::new(false), error_sink: RefCell::new(None),
+                errors: RefCell::new(VecDeque::new()) }),
+            upstream: RefCell::new(Vec::new()),
+            scheduler: Scheduler::new(),
+            rank: Cell::new(0)
+        }
+    }
+
+    /// The position of the node in the topological order of its graph, 0 for sources.
+    pub fn rank(&self)->u32 {
+        self.rank.get()
+    }
+
+    /// Registers `source` as an input of this node: the graphs are merged into one scheduler
+    ///   and the rank of this node is raised above the rank of the source.
+    pub fn depends_on<M2:Clone+'static>(&self, source: &MessageListeners<'listener, M2>) {
+        source.scheduler.union(&self.scheduler);
+        self.rank.set(self.rank.get().max(source.rank.get()+1));
+    }
+
+    /// Runs `f` at the rank of this node, after all sources have delivered the current update.
+    pub fn schedule(&self, f: impl FnOnce()+'listener) {
+        self.scheduler.schedule(self.rank.get(), f);
+        self.scheduler.run();
     }
 
     /// This method takes a function object and adds it to the vector of listeners.
-    pub fn listen(&self, f: impl FnMut(M)+'listener)->usize {
-        self.listeners.borrow_mut().push(Some(Box::new(f)));
-        self.listeners.borrow().len()-1
+    ///
+    /// Slots of cancelled listeners are reused, except while a message is being delivered.
+    pub fn listen(&self, mut f: impl FnMut(M)+'listener)->Subscription<'listener> {
+        self.add_listener(Box::new(move |m| {
+            f(m);
+            Ok(())
+        }), ErrorPolicy::Report)
+    }
+
+    /// Adds a listener that can fail.  Errors are reported to the error sink together with
+    ///   the listener id and the message, then `policy` decides what happens next.
+    pub fn try_listen<E: Into<Box<dyn Error>>>(&self, mut f: impl FnMut(M)->Result<(), E>+'listener,
+            policy: ErrorPolicy)->Subscription<'listener> {
+        self.add_listener(Box::new(move |m| f(m).map_err(|e| Rc::from(e.into()))), policy)
+    }
+
+    fn add_listener(&self, listener: Listener<'listener, M>, policy: ErrorPolicy)->Subscription<'listener> {
+        let state=&self.state;
+        let reused=if state.dispatching.get() { None } else { state.free.borrow_mut().pop() };
+        let mut slots=state.slots.borrow_mut();
+        let index=match reused {
+            Some(index) => {
+                slots[index].listener=Some(listener);
+                slots[index].policy=policy;
+                index
+            },
+            None => {
+                slots.push(Slot { generation: 0, policy, listener: Some(listener) });
+                slots.len()-1
+            }
+        };
+        let state: Rc<dyn CancelListener + 'listener>=self.state.clone();
+        Subscription { state: Some(Rc::downgrade(&state)), index, generation: slots[index].generation }
+    }
+
+    /// Sets the function that receives the errors of fallible listeners.
+    ///
+    /// Until a sink is set, the last 64 errors are kept and can be read with `take_errors`,
+    ///   older ones are dropped.
+    pub fn set_error_sink(&self, f: impl FnMut(ListenerError<M>)+'listener) {
+        *self.state.error_sink.borrow_mut()=Some(Box::new(f));
+    }
+
+    /// Returns the errors reported while no error sink was set.
+    pub fn take_errors(&self)->Vec<ListenerError<M>> {
+        self.state.errors.borrow_mut().drain(..).collect()
+    }
+
+    /// Keeps a subscription alive for as long as this `MessageListeners` lives.
+    ///
+    /// Operators use it to hold the subscription to their source, so that dropping the
+    ///   derived collection disconnects it.
+    pub fn hold(&self, subscription: Subscription<'listener>) {
+        self.upstream.borrow_mut().push(subscription);
     }
-    
+
     /// This method sends a message to all listeners in the vector.
+    ///
+    /// If an update is already propagating, the message is queued and this method returns
+    ///   immediately; the outermost `send` delivers it.
     pub fn send(&self, message: M) {
-        for listener in self.listeners.borrow_mut().iter_mut() {
-            if let Some(listener2)=listener {
-                (*listener2)(message.clone());
+        let state=&*self.state;
+        state.pending.borrow_mut().push_back(message);
+        if state.dispatching.get() || state.scheduled.replace(true) {
+            return;
+        }
+        let sclone=self.state.clone();
+        self.schedule(move || sclone.dispatch());
+    }
+}
+
+impl<'listener, M:Clone> ListenersState<'listener, M> {
+    fn dispatch(&self) {
+        let state=self;
+        state.scheduled.set(false);
+        state.dispatching.set(true);
+        let _guard=DispatchGuard(state);
+        loop {
+            let next=state.pending.borrow_mut().pop_front();
+            let Some(message)=next else {
+                break;
+            };
+            let count=state.slots.borrow().len();
+            for i in 0..count {
+                let (listener, policy, generation)={
+                    let mut slots=state.slots.borrow_mut();
+                    let slot=&mut slots[i];
+                    (slot.listener.take(), slot.policy, slot.generation)
+                };
+                let Some(mut listener)=listener else {
+                    continue;
+                };
+                state.running.set(Some(i));
+                let mut result=listener(message.clone());
+                let mut attempts=1;
+                if let ErrorPolicy::Retry(retries)=policy {
+                    while result.is_err() && attempts<=retries && !state.running_cancelled.get() {
+                        attempts+=1;
+                        result=listener(message.clone());
+                    }
+                }
+                state.running.set(None);
+                let dropped=result.is_err() && policy==ErrorPolicy::DropListener;
+                if state.running_cancelled.replace(false) || dropped {
+                    drop(listener);
+                    state.release(i);
+                } else {
+                    state.slots.borrow_mut()[i].listener=Some(listener);
+                }
+                if let Err(error)=result {
+                    let listener=ListenerId { index: i, generation };
+                    state.report(ListenerError { listener, message: message.clone(), error, attempts });
+                    if policy==ErrorPolicy::StopPropagation {
+                        break;
+                    }
+                }
             }
         }
     }
 
-    pub fn cancel(&self, i: usize) {
-        self.listeners.borrow_mut()[i] = None;
+    fn report(&self, error: ListenerError<M>) {
+        let sink=self.error_sink.borrow_mut().take();
+        let Some(mut sink)=sink else {
+            let mut errors=self.errors.borrow_mut();
+            if errors.len()==KEPT_ERRORS {
+                errors.pop_front();
+            }
+            errors.push_back(error);
+            return;
+        };
+        sink(error);
+        // The sink may have been replaced while it was running.
+        let mut slot=self.error_sink.borrow_mut();
+        if slot.is_none() {
+            *slot=Some(sink);
+        }
     }
 }
 
 
 pub trait MessageListenersInterface<'listener, M:Clone+'static> : Sized {
     fn listeners(&self)->&MessageListeners<'listener, M>;
-    fn listen(&self, f: impl FnMut(M)+'listener) {
-        println!("MessageListenersInterface::listen");
-        MessageListeners::listen(self.listeners(), f);
+    fn listen(&self, f: impl FnMut(M)+'listener)->Subscription<'listener> {
+        MessageListeners::listen(self.listeners(), f)
+    }
+    fn try_listen<E: Into<Box<dyn Error>>>(&self, f: impl FnMut(M)->Result<(), E>+'listener,
+            policy: ErrorPolicy)->Subscription<'listener> {
+        MessageListeners::try_listen(self.listeners(), f, policy)
     }
-     fn map<M2:Clone+'static>(&self, f: impl Fn(M)->M2 + 'listener)->Rc<MessageListeners<'listener, M2>> {
+    fn map<M2:Clone+'static>(&self, f: impl Fn(M)->M2 + 'listener)->Rc<MessageListeners<'listener, M2>> {
         let r = Rc::new(MessageListeners::new());
-        let rclone=r.clone();
-        self.listen(move |m|  rclone.send(f(m)));
+        r.depends_on(self.listeners());
+        let weak=Rc::downgrade(&r);
+        r.hold(self.listen(move |m| if let Some(r)=weak.upgrade() { r.send(f(m)) }));
         r
     }
     fn filter(&self, f: impl Fn(&M)->bool + 'listener)->Rc<MessageListeners<'listener, M>> {
         let r = Rc::new(MessageListeners::new());
-        let rclone=r.clone();
-        self.listeners().listen(move |m| if f(&m) { rclone.send(m)});
+        r.depends_on(self.listeners());
+        let weak=Rc::downgrade(&r);
+        r.hold(self.listen(move |m| if f(&m) { if let Some(r)=weak.upgrade() { r.send(m) } }));
         r
     }
+    /// Sends every item of the iterator returned by `f`.
+    fn flat_map<M2:Clone+'static, I: IntoIterator<Item=M2>>(&self, f: impl Fn(M)->I + 'listener)
+            ->Rc<MessageListeners<'listener, M2>> {
+        let r = Rc::new(MessageListeners::new());
+        r.depends_on(self.listeners());
+        let weak=Rc::downgrade(&r);
+        r.hold(self.listen(move |m| if let Some(r)=weak.upgrade() {
+            for m2 in f(m) {
+                r.send(m2);
+            }
+        }));
+        r
+    }
+    /// Maps and filters in one step: `None` results are dropped.
+    fn filter_map<M2:Clone+'static>(&self, f: impl Fn(M)->Option<M2> + 'listener)->Rc<MessageListeners<'listener, M2>> {
+        let r = Rc::new(MessageListeners::new());
+        r.depends_on(self.listeners());
+        let weak=Rc::downgrade(&r);
+        r.hold(self.listen(move |m| if let Some(m2)=f(m) { if let Some(r)=weak.upgrade() { r.send(m2) } }));
+        r
+    }
+    /// Sends the messages of both `self` and `other`.
+    fn merge(&self, other: &impl MessageListenersInterface<'listener, M>)->Rc<MessageListeners<'listener, M>> {
+        let r = Rc::new(MessageListeners::new());
+        for source in [self.listeners(), other.listeners()] {
+            r.depends_on(source);
+            let weak=Rc::downgrade(&r);
+            r.hold(source.listen(move |m| if let Some(r)=weak.upgrade() { r.send(m) }));
+        }
+        r
+    }
+    /// Like `map`, but `f` can update a state that is kept between messages.
+    fn scan<S:'listener, M2:Clone+'static>(&self, initial: S, mut f: impl FnMut(&mut S, M)->M2 + 'listener)
+            ->Rc<MessageListeners<'listener, M2>> {
+        let mut state=initial;
+        let r = Rc::new(MessageListeners::new());
+        r.depends_on(self.listeners());
+        let weak=Rc::downgrade(&r);
+        r.hold(self.listen(move |m| if let Some(r)=weak.upgrade() { r.send(f(&mut state, m)) }));
+        r
+    }
+    /// Sends the accumulated value after every message.
+    fn fold<A:Clone+'static>(&self, initial: A, mut f: impl FnMut(A, M)->A + 'listener)->Rc<MessageListeners<'listener, A>> {
+        self.scan(initial, move |acc, m| {
+            *acc=f(acc.clone(), m);
+            acc.clone()
+        })
+    }
+    /// Calls `f` with every message and passes the messages on unchanged.
+    fn inspect(&self, f: impl Fn(&M) + 'listener)->Rc<MessageListeners<'listener, M>> {
+        self.filter(move |m| {
+            f(m);
+            true
+        })
+    }
+    /// Sends only the first `n` messages.
+    fn take(&self, n: usize)->Rc<MessageListeners<'listener, M>> {
+        let seen=Cell::new(0);
+        self.filter(move |_| {
+            seen.set(seen.get()+1);
+            seen.get()<=n
+        })
+    }
+    /// Drops the first `n` messages.
+    fn skip(&self, n: usize)->Rc<MessageListeners<'listener, M>> {
+        let seen=Cell::new(0);
+        self.filter(move |_| {
+            seen.set(seen.get()+1);
+            seen.get()>n
+        })
+    }
+    /// Drops messages that are equal to the previous message.
+    fn dedup(&self)->Rc<MessageListeners<'listener, M>> where M: PartialEq {
+        let last: RefCell<Option<M>>=RefCell::new(None);
+        self.filter(move |m| {
+            let mut last=last.borrow_mut();
+            if last.as_ref()==Some(m) {
+                return false;
+            }
+            *last=Some(m.clone());
+            true
+        })
+    }
+    /// Sends the latest messages of `self` and `other` as a pair whenever either of them
+    ///   sends, once both have sent at least one message.
+    fn zip_latest<M2:Clone+'static>(&self, other: &impl MessageListenersInterface<'listener, M2>)
+            ->Rc<MessageListeners<'listener, (M, M2)>> {
+        let r = Rc::new(MessageListeners::new());
+        r.depends_on(self.listeners());
+        r.depends_on(other.listeners());
+        let latest: Rc<RefCell<(Option<M>, Option<M2>)>>=Rc::new(RefCell::new((None, None)));
+        let (weak, latest1)=(Rc::downgrade(&r), latest.clone());
+        r.hold(self.listen(move |m| {
+            let pair={
+                let mut latest=latest1.borrow_mut();
+                latest.0=Some(m);
+                latest.1.clone().map(|m2| (latest.0.clone().unwrap(), m2))
+            };
+            if let (Some(pair), Some(r))=(pair, weak.upgrade()) { r.send(pair) }
+        }));
+        let weak=Rc::downgrade(&r);
+        r.hold(other.listen(move |m2| {
+            let pair={
+                let mut latest=latest.borrow_mut();
+                latest.1=Some(m2);
+                latest.0.clone().map(|m| (m, latest.1.clone().unwrap()))
+            };
+            if let (Some(pair), Some(r))=(pair, weak.upgrade()) { r.send(pair) }
+        }));
+        r
+    }
+    /// Returns a `Stream` of the messages, see `ListenerStream`.
+    fn stream(&self, capacity: usize, overflow: OverflowPolicy)->ListenerStream<'listener, M> {
+        ListenerStream::new(self.listeners(), capacity, overflow)
+    }
 }
 
 impl<'listener, M:Clone+'static> MessageListenersInterface<'listener, M> for MessageListeners<'listener, M> {
@@ -67,7 +493,7 @@ fn test_message_listeners() {
         };
 
     // Act
-    ml.listen(f);
+    let _subscription=ml.listen(f);
     ml.send(1);
     ml.send(2);
     ml.send(3);
@@ -88,8 +514,9 @@ fn test_message_listeners_map() {
     };
     let g = |m: i32| m * 2;
 
-    // There's a bug in the compiler here.  If you don't create a temporary variable, the compiler SEGFAULTS.
-    ml.map(g).listen(f);
+    // The mapped listeners have to be kept alive, dropping them disconnects them from ml.
+    let mapped=ml.map(g);
+    let _subscription=mapped.listen(f);
     ml.send(1);
     ml.send(2);
     ml.send(3);
@@ -112,7 +539,7 @@ fn test_message_listeners_filter() {
 
     // Act
     let gg=ml.filter(g);
-    gg.listen(f);
+    let _subscription=gg.listen(f);
     ml.send(1);
     ml.send(2);
     ml.send(3);
@@ -122,3 +549,239 @@ fn test_message_listeners_filter() {
   // Assert
   assert_eq!(*messages, vec![2, 4]);
 }
+
+#[test]
+fn test_message_listeners_reentrant_send() {
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let ml = Rc::new(MessageListeners::new());
+    let weak_ml = Rc::downgrade(&ml);
+    let mclone = messages.clone();
+    ml.listen(move |m: i32| {
+        mclone.borrow_mut().push(("a", m));
+        if m < 3 {
+            weak_ml.upgrade().unwrap().send(m + 1);
+        }
+    }).detach();
+    let mclone = messages.clone();
+    ml.listen(move |m: i32| mclone.borrow_mut().push(("b", m))).detach();
+    ml.send(1);
+    assert_eq!(*messages.borrow(), vec![("a", 1), ("b", 1), ("a", 2), ("b", 2), ("a", 3), ("b", 3)]);
+}
+
+#[test]
+fn test_message_listeners_listen_and_cancel_while_sending() {
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let ml = Rc::new(MessageListeners::new());
+    let weak_ml = Rc::downgrade(&ml);
+    let subscription = Rc::new(RefCell::new(None));
+    let sclone = subscription.clone();
+    let mclone = messages.clone();
+    *subscription.borrow_mut() = Some(ml.listen(move |m: i32| {
+        mclone.borrow_mut().push(("once", m));
+        let ml = weak_ml.upgrade().unwrap();
+        sclone.borrow_mut().take();
+        let mclone = mclone.clone();
+        ml.listen(move |m| mclone.borrow_mut().push(("late", m))).detach();
+    }));
+    ml.send(1);
+    ml.send(2);
+    assert_eq!(*messages.borrow(), vec![("once", 1), ("late", 2)]);
+}
+
+#[test]
+fn test_subscription_drop_and_slot_reuse() {
+    let messages = RefCell::new(Vec::new());
+    let ml = MessageListeners::new();
+    let subscription = ml.listen(|m: i32| messages.borrow_mut().push(m));
+    ml.send(1);
+    drop(subscription);
+    ml.send(2);
+    let _subscription = ml.listen(|m: i32| messages.borrow_mut().push(m * 10));
+    ml.send(3);
+    assert_eq!(ml.state.slots.borrow().len(), 1);
+    assert_eq!(*messages.borrow(), vec![1, 30]);
+}
+
+#[test]
+fn test_stale_subscription_does_not_cancel_reused_slot() {
+    let messages = RefCell::new(Vec::new());
+    let ml = MessageListeners::new();
+    let first = ml.listen(|_m: i32| {});
+    let index = first.index;
+    ml.state.cancel(index, first.generation);
+    let _second = ml.listen(|m: i32| messages.borrow_mut().push(m));
+    drop(first);
+    ml.send(1);
+    assert_eq!(*messages.borrow(), vec![1]);
+}
+
+#[test]
+fn test_dropping_mapped_listeners_disconnects() {
+    let ml = MessageListeners::new();
+    let mapped = ml.map(|m: i32| m * 2);
+    assert_eq!(ml.state.slots.borrow().iter().filter(|slot| slot.listener.is_some()).count(), 1);
+    drop(mapped);
+    assert_eq!(ml.state.slots.borrow().iter().filter(|slot| slot.listener.is_some()).count(), 0);
+    ml.send(1);
+}
+
+#[test]
+fn test_try_listen_error_policies() {
+    let messages = RefCell::new(Vec::new());
+    let ml = MessageListeners::new();
+    let report = ml.try_listen(|m: i32| if m == 2 { Err("report") } else { Ok(()) }, ErrorPolicy::Report);
+    let _dropped = ml.try_listen(|m: i32| if m == 1 { Err("drop") } else { Ok(()) }, ErrorPolicy::DropListener);
+    let _stop = ml.try_listen(|m: i32| if m == 3 { Err("stop") } else { Ok(()) }, ErrorPolicy::StopPropagation);
+    let _last = ml.listen(|m: i32| messages.borrow_mut().push(m));
+    ml.send(1);
+    ml.send(2);
+    ml.send(3);
+    assert_eq!(*messages.borrow(), vec![1, 2]);
+    let errors = ml.take_errors();
+    let errors: Vec<_> = errors.iter().map(|e| (e.message, e.error.to_string(), e.attempts)).collect();
+    assert_eq!(errors, vec![(1, "drop".to_string(), 1), (2, "report".to_string(), 1), (3, "stop".to_string(), 1)]);
+    assert_eq!(ml.take_errors().len(), 0);
+    assert_eq!(ml.state.slots.borrow().iter().filter(|slot| slot.listener.is_some()).count(), 3);
+    let id = report.id();
+    let reported = Rc::new(RefCell::new(Vec::new()));
+    let reported2 = reported.clone();
+    ml.set_error_sink(move |e| reported2.borrow_mut().push((e.listener, e.message)));
+    ml.send(2);
+    assert_eq!(*reported.borrow(), vec![(id, 2)]);
+}
+
+#[test]
+fn test_try_listen_retry() {
+    let calls = Cell::new(0);
+    let ml = MessageListeners::new();
+    let _subscription = ml.try_listen(|m: i32| {
+        calls.set(calls.get() + 1);
+        if calls.get() < m { Err(format!("attempt {}", calls.get())) } else { Ok(()) }
+    }, ErrorPolicy::Retry(2));
+    ml.send(3);
+    assert_eq!(calls.get(), 3);
+    assert_eq!(ml.take_errors().len(), 0);
+    calls.set(0);
+    ml.send(5);
+    assert_eq!(calls.get(), 3);
+    let errors = ml.take_errors();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].attempts, 3);
+    assert_eq!(errors[0].error.to_string(), "attempt 3");
+}
+
+#[test]
+fn test_errors_without_sink_are_bounded() {
+    let ml = MessageListeners::new();
+    let _subscription = ml.try_listen(|_: i32| Err("failed"), ErrorPolicy::Report);
+    for i in 0..100 {
+        ml.send(i);
+    }
+    let errors = ml.take_errors();
+    assert_eq!(errors.len(), KEPT_ERRORS);
+    assert_eq!((errors[0].message, errors[KEPT_ERRORS - 1].message), (36, 99));
+}
+
+#[test]
+fn test_message_listeners_flat_map_and_filter_map() {
+  let mut messages = Vec::new();
+  {
+    let ml = MessageListeners::new();
+    let flat = ml.flat_map(|m: i32| vec![m; m as usize]);
+    let odd = flat.filter_map(|m: i32| if m % 2 == 1 { Some(m * 10) } else { None });
+    let _subscription = odd.listen(|m| messages.push(m));
+    ml.send(1);
+    ml.send(2);
+    ml.send(3);
+  }
+  assert_eq!(*messages, vec![10, 30, 30, 30]);
+}
+
+#[test]
+fn test_message_listeners_merge() {
+  let mut messages = Vec::new();
+  {
+    let ml = MessageListeners::new();
+    let ml2 = MessageListeners::new();
+    let merged = ml.merge(&ml2);
+    let _subscription = merged.listen(|m: i32| messages.push(m));
+    ml.send(1);
+    ml2.send(2);
+    ml.send(3);
+  }
+  assert_eq!(*messages, vec![1, 2, 3]);
+}
+
+#[test]
+fn test_message_listeners_scan_and_fold() {
+  let mut scanned = Vec::new();
+  let mut folded = Vec::new();
+  {
+    let ml = MessageListeners::new();
+    let differences = ml.scan(0, |last: &mut i32, m: i32| {
+        let d = m - *last;
+        *last = m;
+        d
+    });
+    let sums = ml.fold(0, |acc, m: i32| acc + m);
+    let _subscription = differences.listen(|m| scanned.push(m));
+    let _subscription2 = sums.listen(|m| folded.push(m));
+    ml.send(1);
+    ml.send(4);
+    ml.send(2);
+  }
+  assert_eq!(*scanned, vec![1, 3, -2]);
+  assert_eq!(*folded, vec![1, 5, 7]);
+}
+
+#[test]
+fn test_message_listeners_inspect_take_skip() {
+  let inspected = RefCell::new(Vec::new());
+  let mut messages = Vec::new();
+  {
+    let ml = MessageListeners::new();
+    // Every stage has to be kept alive, dropping one disconnects the stages after it.
+    let inspect = ml.inspect(|m: &i32| inspected.borrow_mut().push(*m));
+    let skipped = inspect.skip(1);
+    let window = skipped.take(2);
+    let _subscription = window.listen(|m| messages.push(m));
+    for i in 1..=5 {
+        ml.send(i);
+    }
+  }
+  assert_eq!(*inspected.borrow(), vec![1, 2, 3, 4, 5]);
+  assert_eq!(*messages, vec![2, 3]);
+}
+
+#[test]
+fn test_message_listeners_dedup() {
+  let mut messages = Vec::new();
+  {
+    let ml = MessageListeners::new();
+    let deduped = ml.dedup();
+    let _subscription = deduped.listen(|m: i32| messages.push(m));
+    for i in [1, 1, 2, 2, 2, 1, 3, 3] {
+        ml.send(i);
+    }
+  }
+  assert_eq!(*messages, vec![1, 2, 1, 3]);
+}
+
+#[test]
+fn test_message_listeners_zip_latest() {
+  let mut messages = Vec::new();
+  {
+    let ml = MessageListeners::new();
+    let ml2 = MessageListeners::new();
+    let zipped = ml.zip_latest(&ml2);
+    let _subscription = zipped.listen(|m: (i32, &str)| messages.push(m));
+    ml.send(1);
+    ml.send(2);
+    ml2.send("a");
+    ml.send(3);
+    ml2.send("b");
+    drop(zipped);
+    ml.send(4);
+  }
+  assert_eq!(*messages, vec![(2, "a"), (3, "a"), (3, "b")]);
+}
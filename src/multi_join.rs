@@ -0,0 +1,284 @@
+use std::{cell::RefCell, cmp::Ordering, collections::{HashMap, HashSet}, hash::Hash, rc::Rc};
+
+use crate::{message_listeners::{MessageListeners, MessageListenersInterface, Subscription}, multi_set::{MultiSetMessageListeners, MultiSetModifyMessage},
+    queryable_streaming_multi_map::{old_counts, push_value_changes, send_key_changes, QuerableStreamingMultiMap, QuerableStreamingMultiMapGetter},
+    rc_borrow::{Borrow, RcBorrow}};
+#[cfg(test)]
+use crate::queryable_streaming_multi_map::StreamingHashMultiMapWithCount;
+
+/// Groups the changes of an input of a join by key, adding the keys that weren't seen yet to `keys`.
+fn changes_by_key<K: Eq+Hash+Clone, V>(messages: Vec<MultiSetModifyMessage<(K, V)>>, keys: &mut Vec<K>, seen: &mut HashSet<K>)->
+        HashMap<K, Vec<(V, i64)>> where V: Eq+Hash+Clone {
+    let mut r: HashMap<K, Vec<(V, i64)>>=HashMap::new();
+    for ((key, value), diff) in MultiSetModifyMessage::consolidate(messages) {
+        if seen.insert(key.clone()) {
+            keys.push(key.clone());
+        }
+        r.entry(key).or_default().push((value, diff));
+    }
+    r
+}
+
+/// The values with their multiplicities as weights.
+fn weights<V: Clone>(counts: &HashMap<V, u64>)->Vec<(V, i64)> {
+    counts.iter().map(|(v, count)| (v.clone(), *count as i64)).collect()
+}
+
+/// Defines a join of a fixed number of inputs on the same key, with the values of the inputs
+///   in one flat tuple.
+macro_rules! multi_join {
+    ($join:ident, $getter:ident, $state:ident; $($idx:tt $name:ident $source:ident $value:ident $source_getter:ident),+) => {
+        /// A join of several inputs on the same key that sends the values of the inputs as one
+        ///   flat tuple, see `QuerableStreamingMultiMap::join3`.
+        ///
+        /// Like `JoinQuerableStreamingMultiMap`, the join isn't materialized: `get` reads
+        ///   through to the inputs, and the changes of an update are sent once, when all inputs
+        ///   are up to date, looking up every input once per changed key.
+        pub struct $join<'source, 'listener, 'last_source, K: Eq+Hash+Clone+'static, $($value: Eq+Hash+Clone+'static,)+
+                $($source: QuerableStreamingMultiMap<'source, 'listener, K, $value>,)+> {
+            _subscriptions: Vec<Subscription<'listener>>,
+            // The state and the getter hold clones of the getters, so they have to be dropped before the RcBorrows.
+            state: Rc<$state<'listener, K, $($value,)+ $($source::Getter,)+>>,
+            getter: $getter<K, $($value,)+ $($source::Getter,)+>,
+            _source_getters: ($(RcBorrow<'last_source, $source::Getter>,)+)
+        }
+
+        struct $state<'listener, K: Eq+Hash+Clone+'static, $($value: Eq+Hash+Clone+'static,)+ $($source_getter,)+> {
+            listeners: MultiSetMessageListeners<'listener, (K, ($($value,)+))>,
+            getter: $getter<K, $($value,)+ $($source_getter,)+>,
+            // The changes of every input that arrived during the current update, and whether
+            //   the join is scheduled.
+            pending: RefCell<(($(Vec<MultiSetModifyMessage<(K, $value)>>,)+), bool)>
+        }
+
+        pub struct $getter<K, $($value,)+ $($source_getter,)+> {
+            source_getters: ($(Rc<Borrow<$source_getter>>,)+),
+            phantom_data: std::marker::PhantomData<(K, $($value,)+)>
+        }
+
+        impl<K: Eq+Hash+Clone+'static, $($value: Eq+Hash+Clone+'static,)+ $($source_getter: QuerableStreamingMultiMapGetter<K, $value>,)+>
+                $getter<K, $($value,)+ $($source_getter,)+> {
+            /// Every combination of the weighted values of the inputs, with the product of their weights.
+            fn product($($name: &[($value, i64)],)+)->Vec<(($($value,)+), i64)> {
+                let combinations=1 $(*$name.len())+;
+                (0..combinations).map(|combination| {
+                    // The index of the value of every input, as digits of the combination.
+                    let mut rest=combination;
+                    let mut index=|len: usize| {
+                        let i=rest%len;
+                        rest/=len;
+                        i
+                    };
+                    let ($($name,)+)=($(&$name[index($name.len())],)+);
+                    (($($name.0.clone(),)+), 1 $(*$name.1)+)
+                }).collect()
+            }
+            fn counts_of_inputs(&self, key: &K)->($(HashMap<$value, u64>,)+) {
+                ($(self.source_getters.$idx.counts(key),)+)
+            }
+        }
+
+        impl<K: Eq+Hash+Clone+'static, $($value: Eq+Hash+Clone+'static,)+ $($source_getter: QuerableStreamingMultiMapGetter<K, $value>,)+>
+                QuerableStreamingMultiMapGetter<K, ($($value,)+)> for $getter<K, $($value,)+ $($source_getter,)+> {
+            fn get(&self, key: &K)->HashSet<($($value,)+)> {
+                self.counts(key).into_keys().collect()
+            }
+            fn items(&self)->Vec<(K, ($($value,)+))> {
+                self.weighted_items().into_iter().map(|(pair, _)| pair).collect()
+            }
+            fn counts(&self, key: &K)->HashMap<($($value,)+), u64> {
+                let counts=self.counts_of_inputs(key);
+                Self::product($(&weights(&counts.$idx),)+).into_iter().map(|(values, count)| (values, count as u64)).collect()
+            }
+            // Every value of the join has a value of the first input, so only its keys are visited.
+            fn weighted_items(&self)->Vec<((K, ($($value,)+)), u64)> {
+                let mut keys=HashSet::new();
+                let mut r=Vec::new();
+                for (k, _) in self.source_getters.0.items() {
+                    if keys.insert(k.clone()) {
+                        r.extend(self.counts(&k).into_iter().map(|(values, count)| ((k.clone(), values), count)));
+                    }
+                }
+                r
+            }
+        }
+
+        impl<'listener, K: Eq+Hash+Clone+'static, $($value: Eq+Hash+Clone+'static,)+
+                $($source_getter: QuerableStreamingMultiMapGetter<K, $value>+'listener,)+>
+                $state<'listener, K, $($value,)+ $($source_getter,)+> {
+            fn push(self: &Rc<Self>, f: impl FnOnce(&mut ($(Vec<MultiSetModifyMessage<(K, $value)>>,)+))) {
+                let schedule={
+                    let mut pending=self.pending.borrow_mut();
+                    f(&mut pending.0);
+                    !std::mem::replace(&mut pending.1, true)
+                };
+                if schedule {
+                    let weak=Rc::downgrade(self);
+                    self.listeners.schedule(move || if let Some(state)=weak.upgrade() { state.flush() });
+                }
+            }
+
+            /// Sends the changes of the join for the buffered input changes of every key: for
+            ///   every input, its changes joined with the new values of the inputs before it and
+            ///   the old values of the inputs after it.
+            fn flush(&self) {
+                let ($($name,)+)={
+                    let mut pending=self.pending.borrow_mut();
+                    pending.1=false;
+                    std::mem::take(&mut pending.0)
+                };
+                let cleared=false $(|| $name.iter().any(|message| matches!(message.first(), Some(MultiSetModifyMessage::Clear(_)))))+;
+                let mut keys=Vec::new();
+                let mut seen=HashSet::new();
+                let ($($name,)+)=($(changes_by_key($name, &mut keys, &mut seen),)+);
+                let mut messages=Vec::new();
+                for key in keys {
+                    let new=self.getter.counts_of_inputs(&key);
+                    let changes=($($name.get(&key).map(Vec::as_slice).unwrap_or(&[]),)+);
+                    let old=($(old_counts(new.$idx.clone(), changes.$idx),)+);
+                    let mut joined=Vec::new();
+                    for i in 0..[$($idx,)+].len() {
+                        if [$(changes.$idx.is_empty(),)+][i] {
+                            continue;
+                        }
+                        let factors=($(match $idx.cmp(&i) {
+                            Ordering::Less=>weights(&new.$idx),
+                            Ordering::Equal=>changes.$idx.to_vec(),
+                            Ordering::Greater=>weights(&old.$idx)
+                        },)+);
+                        joined.extend($getter::<K, $($value,)+ $($source_getter,)+>::product($(&factors.$idx,)+));
+                    }
+                    push_value_changes(&mut messages, key, joined);
+                }
+                send_key_changes(&self.listeners, messages, cleared);
+            }
+        }
+
+        impl<'source, 'listener, 'last_source, K: Eq+Hash+Clone+'static, $($value: Eq+Hash+Clone+'static,)+
+                $($source: QuerableStreamingMultiMap<'source, 'listener, K, $value>,)+>
+                QuerableStreamingMultiMap<'source, 'listener, K, ($($value,)+)> for $join<'source, 'listener, 'last_source, K, $($value,)+ $($source,)+> {
+            type Getter=$getter<K, $($value,)+ $($source::Getter,)+>;
+            fn getter(&self)->&Self::Getter {
+                &self.getter
+            }
+        }
+
+        impl<'source, 'listener, 'last_source, K: Eq+Hash+Clone+'static, $($value: Eq+Hash+Clone+'static,)+
+                $($source: QuerableStreamingMultiMap<'source, 'listener, K, $value>,)+>
+                MessageListenersInterface<'listener, MultiSetModifyMessage<(K, ($($value,)+))>>
+                for $join<'source, 'listener, 'last_source, K, $($value,)+ $($source,)+> {
+            fn listeners(&self)->&MessageListeners<'listener, MultiSetModifyMessage<(K, ($($value,)+))>> {
+                &self.state.listeners
+            }
+        }
+
+        impl<'source, 'listener, 'last_source, K: Eq+Hash+Clone+'static, $($value: Eq+Hash+Clone+'static,)+
+                $($source: QuerableStreamingMultiMap<'source, 'listener, K, $value>,)+>
+                $join<'source, 'listener, 'last_source, K, $($value,)+ $($source,)+> {
+            pub fn new($($name: &'last_source $source,)+)->Self {
+                let source_getters=($(RcBorrow::new($name.getter()),)+);
+                let listeners=MultiSetMessageListeners::new();
+                $(listeners.depends_on($name.listeners());)+
+                let state=Rc::new($state {
+                    listeners,
+                    getter: $getter { source_getters: ($(source_getters.$idx.get(),)+), phantom_data: std::marker::PhantomData },
+                    pending: RefCell::new(Default::default())
+                });
+                let subscriptions=vec![$({
+                    let weak=Rc::downgrade(&state);
+                    $name.listeners().listen(move |message| {
+                        if let Some(state)=weak.upgrade() {
+                            state.push(|pending| pending.$idx.push(message));
+                        }
+                    })
+                },)+];
+                Self {
+                    _subscriptions: subscriptions,
+                    getter: $getter { source_getters: ($(source_getters.$idx.get(),)+), phantom_data: std::marker::PhantomData },
+                    state,
+                    _source_getters: source_getters
+                }
+            }
+        }
+    };
+}
+
+multi_join!(Join3QuerableStreamingMultiMap, Join3QuerableStreamingMultiMapGetter, Join3State;
+    0 source Source V Getter, 1 source2 Source2 V2 Getter2, 2 source3 Source3 V3 Getter3);
+multi_join!(Join4QuerableStreamingMultiMap, Join4QuerableStreamingMultiMapGetter, Join4State;
+    0 source Source V Getter, 1 source2 Source2 V2 Getter2, 2 source3 Source3 V3 Getter3, 3 source4 Source4 V4 Getter4);
+multi_join!(Join5QuerableStreamingMultiMap, Join5QuerableStreamingMultiMapGetter, Join5State;
+    0 source Source V Getter, 1 source2 Source2 V2 Getter2, 2 source3 Source3 V3 Getter3, 3 source4 Source4 V4 Getter4,
+    4 source5 Source5 V5 Getter5);
+
+#[test]
+fn test_join3() {
+    let uid_by_client = StreamingHashMultiMapWithCount::new();
+    let clients_by_uid = uid_by_client.reversed();
+    let follows = StreamingHashMultiMapWithCount::new();
+    let settings = StreamingHashMultiMapWithCount::new();
+    uid_by_client.insert("client", "alice");
+    follows.insert("alice", "bob");
+    let joined_map = clients_by_uid.join3(&follows, &settings);
+    let grouped = joined_map.group_by(|uid, (client, followed, theme)| (client, (uid, followed, theme)));
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let mclone = messages.clone();
+    let _subscription = joined_map.listen(move |message| mclone.borrow_mut().push(message));
+    assert_eq!(joined_map.items(), vec![]);
+    settings.insert("alice", "dark");
+    follows.insert("alice", "carol");
+    settings.set("alice", "light");
+    assert_eq!(messages.borrow()[..2], [MultiSetModifyMessage::InsertOne(("alice", ("client", "bob", "dark"))),
+        MultiSetModifyMessage::InsertOne(("alice", ("client", "carol", "dark")))]);
+    assert!(matches!(&messages.borrow()[2..], [MultiSetModifyMessage::Batch(batch)] if batch.len()==4));
+    let mut items = grouped.items();
+    items.sort();
+    assert_eq!(items, vec![("client", ("alice", "bob", "light")), ("client", ("alice", "carol", "light"))]);
+    uid_by_client.remove("client", "alice");
+    assert_eq!(grouped.items(), vec![]);
+}
+
+#[test]
+fn test_join4_sends_one_message_per_update() {
+    let map1 = StreamingHashMultiMapWithCount::new();
+    let map2 = StreamingHashMultiMapWithCount::new();
+    let map3 = StreamingHashMultiMapWithCount::new();
+    map1.insert(1, "a");
+    map2.insert(1, "b");
+    map3.insert(1, "c");
+    // The same input twice only sends the change once.
+    let joined_map = map1.join4(&map2, &map3, &map1);
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let mclone = messages.clone();
+    let _subscription = joined_map.listen(move |message| mclone.borrow_mut().push(message));
+    assert_eq!(joined_map.get_one(&1), Some(("a", "b", "c", "a")));
+    map1.set(1, "z");
+    assert_eq!(*messages.borrow(), vec![MultiSetModifyMessage::Replace { old: (1, ("a", "b", "c", "a")), new: (1, ("z", "b", "c", "z")) }]);
+}
+
+#[test]
+fn test_join3_multiplies_weights() {
+    let map1 = StreamingHashMultiMapWithCount::new();
+    let map2 = StreamingHashMultiMapWithCount::new();
+    let map3 = StreamingHashMultiMapWithCount::new();
+    let joined_map = map1.join3(&map2, &map3);
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let mclone = messages.clone();
+    let _subscription = joined_map.listen(move |message| mclone.borrow_mut().push(message));
+    map1.add(1, "a", 2);
+    map2.add(1, "b", 3);
+    map3.insert(1, "c");
+    map3.insert(1, "d");
+    let mut items = joined_map.weighted_items();
+    items.sort();
+    assert_eq!(items, vec![((1, ("a", "b", "c")), 6), ((1, ("a", "b", "d")), 6)]);
+    map2.insert(1, "e");
+    assert_eq!(messages.borrow()[..2], [MultiSetModifyMessage::Delta((1, ("a", "b", "c")), 6), MultiSetModifyMessage::Delta((1, ("a", "b", "d")), 6)]);
+    let mut changes = MultiSetModifyMessage::consolidate(messages.borrow()[2..].to_vec());
+    changes.sort();
+    assert_eq!(changes, vec![((1, ("a", "e", "c")), 2), ((1, ("a", "e", "d")), 2)]);
+    map1.remove(1, "a");
+    let mut items = joined_map.weighted_items();
+    items.sort();
+    assert_eq!(items, vec![((1, ("a", "b", "c")), 3), ((1, ("a", "b", "d")), 3), ((1, ("a", "e", "c")), 1), ((1, ("a", "e", "d")), 1)]);
+}
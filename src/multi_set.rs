@@ -1,23 +1,499 @@
-use std::rc::Rc;
+use std::{cell::RefCell, collections::HashMap, hash::Hash, rc::Rc, sync::Arc};
+
+use chrono::{Duration, NaiveDateTime};
 
 use crate::message_listeners::{MessageListeners, MessageListenersInterface};
+use crate::queryable_streaming_multi_map::StreamingHashMultiMapWithCount;
+use crate::time_window::{self, Clock};
+use crate::sync_message_listeners::{SyncMessageListeners, SyncMessageListenersInterface};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum MultiSetModifyMessage<T:Clone> {
     InsertOne(T),
     RemoveOne(T),
+    /// Changes the multiplicity of the item by a signed weight: `Delta(item, 3)` is three
+    ///   inserts and `Delta(item, -3)` is three removes in one message.
+    Delta(T, i64),
+    /// An in-place update: `old` is removed and `new` is inserted as a single change, so
+    ///   clients can show it as an update instead of a delete and an unrelated add.
+    Replace { old: T, new: T },
+    /// The collection was emptied; lists the distinct items that were removed.
+    ///
+    /// Like for any other message, every listed item is removed once, the further copies of
+    ///   items that were inserted more than once are removed by deltas in the same batch (see
+    ///   `remove_all`).  A collection applying it (which may have other sources) loses only
+    ///   those copies, and sends a `Clear` on only if that emptied it too.
+    Clear(Vec<T>),
+    /// Every item stored under one key was removed; lists the distinct items, like `Clear`.
+    ClearKey(Vec<T>),
+    /// Changes that happened together, for example in a transaction.  Listeners should treat
+    ///   them as one update.
+    ///
+    /// The collections send consolidated batches, but a batch that was mapped (or built by
+    ///   hand) may insert and remove the same item; its messages apply in order.
+    Batch(Vec<MultiSetModifyMessage<T>>),
+}
+
+impl<T:Clone> MultiSetModifyMessage<T> {
+    /// Wraps multiple messages into a batch; returns None for no messages and the message
+    ///   itself for a single message.
+    pub fn from_messages(mut messages: Vec<MultiSetModifyMessage<T>>)->Option<Self> {
+        match messages.len() {
+            0 => None,
+            1 => messages.pop(),
+            _ => Some(MultiSetModifyMessage::Batch(messages))
+        }
+    }
+
+    /// Returns the single insert and remove messages, flattening batches.
+    pub fn into_messages(self)->Vec<MultiSetModifyMessage<T>> {
+        let mut r=Vec::new();
+        self.for_each(&mut |message| r.push(message));
+        r
+    }
+
+    /// Returns the items with their weights (+1 for an insert, -1 for a remove), flattening
+    ///   batches without summing up the weights of equal items.
+    pub fn into_weighted(self)->Vec<(T, i64)> {
+        let mut r=Vec::new();
+        self.for_each(&mut |message| match message {
+            MultiSetModifyMessage::InsertOne(item)=>r.push((item, 1)),
+            MultiSetModifyMessage::RemoveOne(item)=>r.push((item, -1)),
+            MultiSetModifyMessage::Delta(item, diff)=>r.push((item, diff)),
+            MultiSetModifyMessage::Replace { old, new }=>r.extend([(old, -1), (new, 1)]),
+            MultiSetModifyMessage::Clear(items) | MultiSetModifyMessage::ClearKey(items)=>
+                r.extend(items.into_iter().map(|item| (item, -1))),
+            MultiSetModifyMessage::Batch(_)=>unreachable!()
+        });
+        r
+    }
+
+    /// The first message that is not a batch.
+    pub fn first(&self)->Option<&Self> {
+        match self {
+            MultiSetModifyMessage::Batch(messages)=>messages.first().and_then(|message| message.first()),
+            message=>Some(message)
+        }
+    }
+
+    /// The message for a change of the multiplicity of the item: an insert or a remove for a
+    ///   weight of 1 or -1, a delta otherwise.
+    pub fn weighted(item: T, diff: i64)->Self {
+        match diff {
+            1=>MultiSetModifyMessage::InsertOne(item),
+            -1=>MultiSetModifyMessage::RemoveOne(item),
+            _=>MultiSetModifyMessage::Delta(item, diff)
+        }
+    }
+
+    /// The message for removing the items with their multiplicities: `message` (`Clear` or
+    ///   `ClearKey`) lists every item once, followed by deltas that remove the further copies.
+    pub fn remove_all(removed: Vec<(T, u64)>, message: impl FnOnce(Vec<T>)->Self)->Option<Self> {
+        if removed.is_empty() {
+            return None;
+        }
+        let copies: Vec<_>=removed.iter().filter(|(_, count)| *count>1)
+            .map(|(item, count)| MultiSetModifyMessage::Delta(item.clone(), 1-*count as i64)).collect();
+        let mut messages=vec![message(removed.into_iter().map(|(item, _)| item).collect())];
+        messages.extend(copies);
+        MultiSetModifyMessage::from_messages(messages)
+    }
+
+    /// Returns the `(old, new)` pairs of the replace messages, flattening batches.
+    pub fn replaces(&self)->Vec<(T, T)> {
+        match self {
+            MultiSetModifyMessage::Replace { old, new }=>vec![(old.clone(), new.clone())],
+            MultiSetModifyMessage::Batch(messages)=>messages.iter().flat_map(|message| message.replaces()).collect(),
+            _=>Vec::new()
+        }
+    }
+
+    /// Expands the message into one insert or remove message per unit of weight.
+    pub fn into_unit_messages(self)->Vec<MultiSetModifyMessage<T>> {
+        let mut r=Vec::new();
+        for (item, diff) in self.into_weighted() {
+            for _ in 0..diff.unsigned_abs() {
+                r.push(if diff>0 { MultiSetModifyMessage::InsertOne(item.clone()) } else { MultiSetModifyMessage::RemoveOne(item.clone()) });
+            }
+        }
+        r
+    }
+
+    /// Calls `f` with every message that is not a batch, flattening batches.
+    pub fn for_each(self, f: &mut impl FnMut(MultiSetModifyMessage<T>)) {
+        match self {
+            MultiSetModifyMessage::Batch(messages)=>{
+                for message in messages {
+                    message.for_each(f);
+                }
+            },
+            message=>f(message)
+        }
+    }
+
+    pub fn map_items<T2:Clone>(self, f: &impl Fn(T)->T2)->MultiSetModifyMessage<T2> {
+        match self {
+            MultiSetModifyMessage::InsertOne(item)=>MultiSetModifyMessage::InsertOne(f(item)),
+            MultiSetModifyMessage::RemoveOne(item)=>MultiSetModifyMessage::RemoveOne(f(item)),
+            MultiSetModifyMessage::Delta(item, diff)=>MultiSetModifyMessage::Delta(f(item), diff),
+            MultiSetModifyMessage::Replace { old, new }=>MultiSetModifyMessage::Replace { old: f(old), new: f(new) },
+            MultiSetModifyMessage::Clear(items)=>MultiSetModifyMessage::Clear(items.into_iter().map(f).collect()),
+            // The mapped items may not share a key anymore.
+            MultiSetModifyMessage::ClearKey(items)=>
+                MultiSetModifyMessage::Batch(items.into_iter().map(|item| MultiSetModifyMessage::RemoveOne(f(item))).collect()),
+            MultiSetModifyMessage::Batch(messages)=>
+                MultiSetModifyMessage::Batch(messages.into_iter().map(|message| message.map_items(f)).collect()),
+        }
+    }
+
+    /// Keeps only the items that `allow` accepts, returns None if nothing is left.
+    ///
+    /// A replace where only one side is accepted becomes a remove of the old item or an
+    ///   insert of the new item.
+    pub fn filter_items(self, allow: &impl Fn(&T)->bool)->Option<Self> {
+        match self {
+            MultiSetModifyMessage::Replace { old, new }=>match (allow(&old), allow(&new)) {
+                (true, true)=>Some(MultiSetModifyMessage::Replace { old, new }),
+                (true, false)=>Some(MultiSetModifyMessage::RemoveOne(old)),
+                (false, true)=>Some(MultiSetModifyMessage::InsertOne(new)),
+                (false, false)=>None
+            },
+            MultiSetModifyMessage::Clear(items)=>Self::non_empty(items.into_iter().filter(|item| allow(item)).collect(), MultiSetModifyMessage::Clear),
+            MultiSetModifyMessage::ClearKey(items)=>
+                Self::non_empty(items.into_iter().filter(|item| allow(item)).collect(), MultiSetModifyMessage::ClearKey),
+            MultiSetModifyMessage::InsertOne(item) | MultiSetModifyMessage::RemoveOne(item) |
+                MultiSetModifyMessage::Delta(item, _) if !allow(&item)=>None,
+            MultiSetModifyMessage::Batch(messages)=>MultiSetModifyMessage::from_messages(
+                messages.into_iter().filter_map(|message| message.filter_items(allow)).collect()),
+            message=>Some(message)
+        }
+    }
+
+    fn non_empty(items: Vec<T>, message: impl FnOnce(Vec<T>)->Self)->Option<Self> {
+        (!items.is_empty()).then(|| message(items))
+    }
+
+    /// Replaces every item with the items returned by `f`, keeping inserts as inserts,
+    ///   removes as removes and the weights of deltas.  A replace becomes removes of the
+    ///   items of `old` followed by inserts of the items of `new`, a `ClearKey` becomes removes.
+    pub fn flat_map_items<T2:Clone>(self, f: &impl Fn(T)->Vec<T2>)->Option<MultiSetModifyMessage<T2>> {
+        match self {
+            MultiSetModifyMessage::InsertOne(item)=>MultiSetModifyMessage::from_messages(
+                f(item).into_iter().map(MultiSetModifyMessage::InsertOne).collect()),
+            MultiSetModifyMessage::RemoveOne(item)=>MultiSetModifyMessage::from_messages(
+                f(item).into_iter().map(MultiSetModifyMessage::RemoveOne).collect()),
+            MultiSetModifyMessage::Delta(item, diff)=>MultiSetModifyMessage::from_messages(
+                f(item).into_iter().map(|item| MultiSetModifyMessage::Delta(item, diff)).collect()),
+            MultiSetModifyMessage::Replace { old, new }=>MultiSetModifyMessage::from_messages(
+                f(old).into_iter().map(MultiSetModifyMessage::RemoveOne).chain(f(new).into_iter().map(MultiSetModifyMessage::InsertOne)).collect()),
+            MultiSetModifyMessage::Clear(items)=>
+                MultiSetModifyMessage::<T2>::non_empty(items.into_iter().flat_map(f).collect(), MultiSetModifyMessage::Clear),
+            MultiSetModifyMessage::ClearKey(items)=>MultiSetModifyMessage::from_messages(
+                items.into_iter().flat_map(f).map(MultiSetModifyMessage::RemoveOne).collect()),
+            MultiSetModifyMessage::Batch(messages)=>MultiSetModifyMessage::from_messages(
+                messages.into_iter().filter_map(|message| message.flat_map_items(f)).flat_map(|message| message.into_messages()).collect()),
+        }
+    }
+}
+
+impl<T:Clone+Eq+Hash> MultiSetModifyMessage<T> {
+    /// Sums up the weights of the messages per item (+1 for an insert, -1 for a remove),
+    ///   in the order the items first appeared, leaving out items whose changes cancel out.
+    pub fn consolidate(messages: impl IntoIterator<Item=Self>)->Vec<(T, i64)> {
+        let mut order=Vec::new();
+        let mut diffs: HashMap<T, i64>=HashMap::new();
+        for message in messages {
+            for (item, diff) in message.into_weighted() {
+                let entry=diffs.entry(item.clone()).or_insert_with(|| {
+                    order.push(item);
+                    0
+                });
+                *entry+=diff;
+            }
+        }
+        order.into_iter().filter_map(|item| {
+            let diff=diffs[&item];
+            (diff!=0).then_some((item, diff))
+        }).collect()
+    }
+
+    /// Applies the message to the multiplicities of a collection, without letting them go
+    ///   below 0, and returns the message for the listeners of the collection: the changes
+    ///   that were made, with replacements sent as `Replace` and a `Clear` that emptied the
+    ///   collection sent on as a `Clear`.
+    pub fn apply_to(self, counts: &mut impl Multiplicities<T>)->Option<Self> {
+        let cleared=matches!(self.first(), Some(MultiSetModifyMessage::Clear(_)));
+        let replaces=self.replaces();
+        let mut messages=Vec::new();
+        for (item, diff) in MultiSetModifyMessage::consolidate([self]) {
+            let old=counts.count(&item);
+            let count=(old as i64+diff).max(0) as u64;
+            if count!=old {
+                counts.set_count(item.clone(), count);
+                messages.push(MultiSetModifyMessage::weighted(item, count as i64-old as i64));
+            }
+        }
+        if cleared && counts.is_empty() {
+            messages=MultiSetModifyMessage::group_removes(messages, MultiSetModifyMessage::Clear);
+        }
+        MultiSetModifyMessage::from_messages(MultiSetModifyMessage::pair_replaces(messages, &replaces))
+    }
+
+    /// Turns consolidated changes back into messages: inserts and removes for a weight of
+    ///   1 and -1, deltas otherwise.
+    pub fn from_consolidated(changes: Vec<(T, i64)>)->Option<Self> {
+        MultiSetModifyMessage::from_messages(changes.into_iter().map(|(item, diff)| MultiSetModifyMessage::weighted(item, diff)).collect())
+    }
+
+    /// Replaces every item with the weighted items returned by `f`, multiplying the weights,
+    ///   for example with the multiplicities of the values an item is joined with.
+    pub fn flat_map_weighted<T2:Clone>(self, f: &impl Fn(T)->Vec<(T2, i64)>)->Option<MultiSetModifyMessage<T2>> {
+        let messages=self.into_weighted().into_iter().flat_map(|(item, diff)| f(item).into_iter()
+            .filter(|(_, weight)| *weight!=0).map(move |(item, weight)| MultiSetModifyMessage::weighted(item, diff*weight))).collect();
+        MultiSetModifyMessage::from_messages(messages)
+    }
+
+    /// Merges the remove of `old` and the insert of `new` into a `Replace` at the position of
+    ///   the remove, for every `(old, new)` pair of `replaces` where both messages are present.
+    pub fn pair_replaces(messages: Vec<Self>, replaces: &[(T, T)])->Vec<Self> {
+        if replaces.is_empty() {
+            return messages;
+        }
+        let mut removes=HashMap::new();
+        let mut inserts=HashMap::new();
+        for (i, message) in messages.iter().enumerate() {
+            match message {
+                MultiSetModifyMessage::RemoveOne(item)=>{removes.entry(item.clone()).or_insert(i);},
+                MultiSetModifyMessage::InsertOne(item)=>{inserts.entry(item.clone()).or_insert(i);},
+                _=>{}
+            }
+        }
+        let mut messages: Vec<Option<Self>>=messages.into_iter().map(Some).collect();
+        for (old, new) in replaces {
+            if !(removes.contains_key(old) && inserts.contains_key(new)) {
+                continue;
+            }
+            let remove=removes.remove(old).unwrap();
+            messages[inserts.remove(new).unwrap()]=None;
+            messages[remove]=Some(MultiSetModifyMessage::Replace { old: old.clone(), new: new.clone() });
+        }
+        messages.into_iter().flatten().collect()
+    }
+
+    /// Turns the messages into a single `Clear` or `ClearKey` message (built by `message`) if
+    ///   they are all removes, see `remove_all`.
+    pub fn group_removes(messages: Vec<Self>, message: impl FnOnce(Vec<T>)->Self)->Vec<Self> {
+        let removed: Option<Vec<_>>=messages.iter().map(|message| match message {
+            MultiSetModifyMessage::RemoveOne(item)=>Some((item.clone(), 1)),
+            MultiSetModifyMessage::Delta(item, diff) if *diff<0=>Some((item.clone(), diff.unsigned_abs())),
+            _=>None
+        }).collect();
+        match removed {
+            Some(removed) if !removed.is_empty()=>MultiSetModifyMessage::remove_all(removed, message).into_iter().collect(),
+            _=>messages
+        }
+    }
+
+    /// Sums up one-at-a-time messages into one message per item, see `consolidate`.
+    pub fn from_unit_messages(messages: impl IntoIterator<Item=Self>)->Option<Self> {
+        MultiSetModifyMessage::from_consolidated(MultiSetModifyMessage::consolidate(messages))
+    }
+}
+
+/// The multiplicities of the items of a collection, see `MultiSetModifyMessage::apply_to`.
+pub trait Multiplicities<T> {
+    /// The multiplicity of the item, 0 if it's not in the collection.
+    fn count(&self, item: &T)->u64;
+    /// Stores the multiplicity of the item, 0 removes it.
+    fn set_count(&mut self, item: T, count: u64);
+    fn is_empty(&self)->bool;
+}
+
+impl<T: Eq+Hash> Multiplicities<T> for HashMap<T, u64> {
+    fn count(&self, item: &T)->u64 {
+        self.get(item).copied().unwrap_or(0)
+    }
+    fn set_count(&mut self, item: T, count: u64) {
+        if count==0 {
+            self.remove(&item);
+        } else {
+            self.insert(item, count);
+        }
+    }
+    fn is_empty(&self)->bool {
+        HashMap::is_empty(self)
+    }
+}
+
+impl<K: Eq+Hash, V: Eq+Hash> Multiplicities<(K, V)> for HashMap<K, HashMap<V, u64>> {
+    fn count(&self, (key, value): &(K, V))->u64 {
+        self.get(key).and_then(|values| values.get(value)).copied().unwrap_or(0)
+    }
+    fn set_count(&mut self, (key, value): (K, V), count: u64) {
+        if count>0 {
+            self.entry(key).or_default().insert(value, count);
+        } else if let Some(values)=self.get_mut(&key) {
+            values.remove(&value);
+            if values.is_empty() {
+                self.remove(&key);
+            }
+        }
+    }
+    fn is_empty(&self)->bool {
+        HashMap::is_empty(self)
+    }
 }
 
 pub type MultiSetMessageListeners<'a, T>=MessageListeners<'a, MultiSetModifyMessage<T>>;
 
 impl<'a, T:Clone+'static> MultiSetMessageListeners<'a, T> {
     pub fn map_items<T2:Clone + 'static>(&self, f: impl Fn(T)->T2+'a)->Rc<MultiSetMessageListeners<'a, T2>> {
-        self.map(move |message| {
-            match message {
-                MultiSetModifyMessage::InsertOne(item)=>MultiSetModifyMessage::InsertOne(f(item)),
-                MultiSetModifyMessage::RemoveOne(item)=>MultiSetModifyMessage::RemoveOne(f(item)),
+        self.map(move |message| message.map_items(&f))
+    }
+}
+
+impl<'a, T:Eq+Hash+Clone+'static> MultiSetMessageListeners<'a, T> {
+    /// Materializes the messages into a multiset that is kept up to date.
+    pub fn multi_set(&self)->Rc<StreamingHashMultiSet<'a, T>> {
+        let r: Rc<StreamingHashMultiSet<'a, T>>=Rc::new(StreamingHashMultiSet::new());
+        r.listeners.depends_on(self);
+        let weak=Rc::downgrade(&r);
+        r.listeners.hold(self.listen(move |message| {
+            if let Some(r)=weak.upgrade() {
+                r.apply(message);
+            }
+        }));
+        r
+    }
+    /// Assigns the items to windows of length `size` that start every `slide`, by the event
+    ///   time returned by `time`.  The result maps the start of every window to its items;
+    ///   when `clock` passes the end of a window, its items are removed.
+    pub fn sliding_window(&self, clock: &Clock<'a>, time: impl Fn(&T)->NaiveDateTime + 'a, size: Duration, slide: Duration)->
+            Rc<StreamingHashMultiMapWithCount<'a, NaiveDateTime, T>> {
+        time_window::sliding_window(self, Vec::new(), clock, time, size, slide)
+    }
+    /// Sliding windows that don't overlap.
+    pub fn tumbling_window(&self, clock: &Clock<'a>, time: impl Fn(&T)->NaiveDateTime + 'a, size: Duration)->
+            Rc<StreamingHashMultiMapWithCount<'a, NaiveDateTime, T>> {
+        self.sliding_window(clock, time, size, size)
+    }
+}
+
+/// A multiset that counts how many times each item was inserted.
+///
+/// Like `StreamingHashMultiMapWithCount`, the listeners get every change of the count of an
+///   item, as inserts and removes for single changes and deltas for larger ones.  The
+///   listeners of `distinct` only hear when an item appears or disappears.
+pub struct StreamingHashMultiSet<'listener, T: Eq+Hash+Clone+'static> {
+    listeners: MultiSetMessageListeners<'listener, T>,
+    distinct: MultiSetMessageListeners<'listener, T>,
+    data: RefCell<HashMap<T, u64>>
+}
+
+impl<'a, T: Eq+Hash+Clone+'static> Default for StreamingHashMultiSet<'a, T> {
+    fn default()->Self {
+        Self::new()
+    }
+}
+
+impl<'a, T: Eq+Hash+Clone+'static> StreamingHashMultiSet<'a, T> {
+    pub fn new()->Self {
+        let (listeners, distinct)=(MultiSetMessageListeners::new(), MultiSetMessageListeners::new());
+        distinct.depends_on(&listeners);
+        Self {listeners, distinct, data: RefCell::new(HashMap::new())}
+    }
+    /// The listeners of the distinct items: they get an insert when the count of an item goes
+    ///   from 0 to at least 1 and a remove when it drops back to 0, but nothing for the other
+    ///   changes of the count.
+    pub fn distinct(&self)->&MultiSetMessageListeners<'a, T> {
+        &self.distinct
+    }
+    pub fn insert(&self, item: T) {
+        self.apply(MultiSetModifyMessage::InsertOne(item));
+    }
+    /// Removes one occurrence of the item, returns false if it wasn't present.
+    pub fn remove(&self, item: T)->bool {
+        if !self.contains(&item) {
+            return false;
+        }
+        self.apply(MultiSetModifyMessage::RemoveOne(item));
+        true
+    }
+    /// Changes the count of the item by `diff`, without going below 0.
+    pub fn add(&self, item: T, diff: i64) {
+        self.apply(MultiSetModifyMessage::Delta(item, diff));
+    }
+    /// Removes every item, sending a `Clear` message, see `MultiSetModifyMessage::remove_all`.
+    pub fn clear(&self) {
+        let data=std::mem::take(&mut *self.data.borrow_mut());
+        if data.is_empty() {
+            return;
+        }
+        let items=data.keys().cloned().collect();
+        if let Some(message)=MultiSetModifyMessage::remove_all(data.into_iter().collect(), MultiSetModifyMessage::Clear) {
+            self.listeners.send(message);
+        }
+        self.distinct.send(MultiSetModifyMessage::Clear(items));
+    }
+    /// Applies a message to the set; the listeners get a single (batch) message with the
+    ///   changes of the counts.  A `Clear` removes only the listed items, see
+    ///   `MultiSetModifyMessage::Clear`.
+    ///
+    /// The data is not borrowed while the listeners run.
+    pub fn apply(&self, message: MultiSetModifyMessage<T>) {
+        let replaces=message.replaces();
+        let Some(message)=message.apply_to(&mut *self.data.borrow_mut()) else {
+            return;
+        };
+        // The items whose count went from or to 0.
+        let distinct=MultiSetModifyMessage::consolidate([message.clone()]).into_iter().filter_map(|(item, diff)| {
+            let count=self.count(&item);
+            if count==0 {
+                Some(MultiSetModifyMessage::RemoveOne(item))
+            } else {
+                (count as i64==diff).then_some(MultiSetModifyMessage::InsertOne(item))
             }
-        })
+        }).collect();
+        let distinct=if matches!(message.first(), Some(MultiSetModifyMessage::Clear(_))) {
+            MultiSetModifyMessage::group_removes(distinct, MultiSetModifyMessage::Clear)
+        } else {
+            MultiSetModifyMessage::pair_replaces(distinct, &replaces)
+        };
+        self.listeners.send(message);
+        if let Some(message)=MultiSetModifyMessage::from_messages(distinct) {
+            self.distinct.send(message);
+        }
+    }
+    pub fn count(&self, item: &T)->u64 {
+        self.data.borrow().get(item).copied().unwrap_or(0)
+    }
+    pub fn contains(&self, item: &T)->bool {
+        self.data.borrow().contains_key(item)
+    }
+    /// The number of distinct items.
+    pub fn len(&self)->usize {
+        self.data.borrow().len()
+    }
+    pub fn is_empty(&self)->bool {
+        self.data.borrow().is_empty()
+    }
+    /// Iterates over a snapshot of the distinct items and their counts.
+    pub fn iter(&self)->std::vec::IntoIter<(T, u64)> {
+        self.data.borrow().iter().map(|(item, count)| (item.clone(), *count)).collect::<Vec<_>>().into_iter()
+    }
+}
+
+impl<'a, T: Eq+Hash+Clone+'static> MessageListenersInterface<'a, MultiSetModifyMessage<T>> for StreamingHashMultiSet<'a, T> {
+    fn listeners(&self)->&MessageListeners<'a, MultiSetModifyMessage<T>> {
+        &self.listeners
+    }
+}
+
+pub type SyncMultiSetMessageListeners<T>=SyncMessageListeners<MultiSetModifyMessage<T>>;
+
+impl<T:Clone+Send+'static> SyncMultiSetMessageListeners<T> {
+    pub fn map_items<T2:Clone+Send+'static>(&self, f: impl Fn(T)->T2+Send+'static)->Arc<SyncMultiSetMessageListeners<T2>> {
+        self.map(move |message| message.map_items(&f))
     }
 }
 
@@ -28,8 +504,140 @@ fn test_multi_set_message_listeners_map_items() {
         let f = |_m: MultiSetModifyMessage<i32>| {};
         let g = |m: i32| m;
         let mi=ml.map_items(g);
-        mi.listen(f);
+        let _subscription=mi.listen(f);
         ml.send(MultiSetModifyMessage::InsertOne(1));
         ml.send(MultiSetModifyMessage::RemoveOne(2));
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_multi_set_modify_message_batch_helpers() {
+    let batch = MultiSetModifyMessage::Batch(vec![
+        MultiSetModifyMessage::InsertOne(1),
+        MultiSetModifyMessage::Batch(vec![MultiSetModifyMessage::RemoveOne(2), MultiSetModifyMessage::InsertOne(3)])]);
+    assert_eq!(batch.clone().map_items(&|i| i * 10).into_messages(), vec![
+        MultiSetModifyMessage::InsertOne(10), MultiSetModifyMessage::RemoveOne(20), MultiSetModifyMessage::InsertOne(30)]);
+    assert_eq!(batch.clone().filter_items(&|i| *i != 1), Some(MultiSetModifyMessage::Batch(vec![
+        MultiSetModifyMessage::RemoveOne(2), MultiSetModifyMessage::InsertOne(3)])));
+    assert_eq!(batch.clone().filter_items(&|i| *i == 2), Some(MultiSetModifyMessage::RemoveOne(2)));
+    assert_eq!(batch.filter_items(&|i| *i > 5), None);
+}
+
+#[test]
+fn test_multi_set_modify_message_deltas() {
+    let units = vec![MultiSetModifyMessage::InsertOne(1), MultiSetModifyMessage::InsertOne(1),
+        MultiSetModifyMessage::InsertOne(2), MultiSetModifyMessage::RemoveOne(2), MultiSetModifyMessage::RemoveOne(3)];
+    let compact = MultiSetModifyMessage::from_unit_messages(units);
+    assert_eq!(compact, Some(MultiSetModifyMessage::Batch(vec![
+        MultiSetModifyMessage::Delta(1, 2), MultiSetModifyMessage::RemoveOne(3)])));
+    let compact = compact.unwrap();
+    assert_eq!(compact.clone().into_unit_messages(), vec![MultiSetModifyMessage::InsertOne(1),
+        MultiSetModifyMessage::InsertOne(1), MultiSetModifyMessage::RemoveOne(3)]);
+    assert_eq!(compact.clone().map_items(&|i| i * 10).into_weighted(), vec![(10, 2), (30, -1)]);
+    assert_eq!(compact.clone().filter_items(&|i| *i == 1), Some(MultiSetModifyMessage::Delta(1, 2)));
+    assert_eq!(compact.flat_map_items(&|i| vec![i, i + 1]).map(|m| m.into_weighted()),
+        Some(vec![(1, 2), (2, 2), (3, -1), (4, -1)]));
+}
+
+#[test]
+fn test_streaming_hash_multi_set() {
+    let set = StreamingHashMultiSet::new();
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let mclone = messages.clone();
+    let _subscription = set.listen(move |message| mclone.borrow_mut().push(message));
+    set.insert("user1");
+    set.insert("user1");
+    set.insert("user2");
+    assert_eq!((set.count(&"user1"), set.len(), set.contains(&"user2")), (2, 2, true));
+    assert!(set.remove("user1"));
+    assert!(set.remove("user2"));
+    assert!(!set.remove("user2"));
+    set.add("user3", 3);
+    let mut items: Vec<_> = set.iter().collect();
+    items.sort();
+    assert_eq!(items, vec![("user1", 1), ("user3", 3)]);
+    assert_eq!(*messages.borrow(), vec![MultiSetModifyMessage::InsertOne("user1"), MultiSetModifyMessage::InsertOne("user1"),
+        MultiSetModifyMessage::InsertOne("user2"), MultiSetModifyMessage::RemoveOne("user1"), MultiSetModifyMessage::RemoveOne("user2"),
+        MultiSetModifyMessage::Delta("user3", 3)]);
+}
+
+#[test]
+fn test_multi_set_from_listeners() {
+    let ml = MultiSetMessageListeners::new();
+    let online = ml.multi_set();
+    ml.send(MultiSetModifyMessage::Batch(vec![MultiSetModifyMessage::InsertOne(1), MultiSetModifyMessage::InsertOne(2)]));
+    ml.send(MultiSetModifyMessage::RemoveOne(1));
+    assert_eq!((online.contains(&1), online.contains(&2), online.len()), (false, true, 1));
+}
+
+#[test]
+fn test_multi_set_modify_message_replace() {
+    let replace = MultiSetModifyMessage::Replace { old: 1, new: 2 };
+    assert_eq!(replace.clone().map_items(&|i| i * 10), MultiSetModifyMessage::Replace { old: 10, new: 20 });
+    assert_eq!(replace.clone().filter_items(&|i| *i == 1), Some(MultiSetModifyMessage::RemoveOne(1)));
+    assert_eq!(replace.clone().filter_items(&|i| *i == 2), Some(MultiSetModifyMessage::InsertOne(2)));
+    assert_eq!(replace.clone().filter_items(&|_| true), Some(replace.clone()));
+    assert_eq!(replace.clone().into_weighted(), vec![(1, -1), (2, 1)]);
+    let set = StreamingHashMultiSet::new();
+    set.insert(1);
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let mclone = messages.clone();
+    let _subscription = set.listen(move |message| mclone.borrow_mut().push(message));
+    set.apply(replace);
+    set.apply(MultiSetModifyMessage::Replace { old: 3, new: 4 });
+    assert_eq!(*messages.borrow(), vec![MultiSetModifyMessage::Replace { old: 1, new: 2 }, MultiSetModifyMessage::InsertOne(4)]);
+}
+
+#[test]
+fn test_multi_set_clear() {
+    let set = StreamingHashMultiSet::new();
+    set.add("user1", 2);
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let mclone = messages.clone();
+    let _subscription = set.listen(move |message| mclone.borrow_mut().push(message));
+    let copy = set.listeners().multi_set();
+    set.insert("user2");
+    // Only the listed items are removed.
+    set.apply(MultiSetModifyMessage::Clear(vec!["user1"]));
+    assert_eq!((set.count(&"user1"), set.count(&"user2")), (1, 1));
+    assert_eq!(messages.borrow()[1], MultiSetModifyMessage::RemoveOne("user1"));
+    set.clear();
+    assert!(set.is_empty() && copy.is_empty());
+    let mut cleared = MultiSetModifyMessage::consolidate([messages.borrow()[2].clone()]);
+    cleared.sort();
+    assert_eq!(cleared, vec![("user1", -1), ("user2", -1)]);
+    assert!(matches!(messages.borrow()[2].first(), Some(MultiSetModifyMessage::Clear(_))));
+    // A clear of all the contents is sent on as a clear.
+    set.add("user1", 2);
+    let copy = set.listeners().multi_set();
+    copy.add("user1", 2);
+    let copy_messages = Rc::new(RefCell::new(Vec::new()));
+    let cclone = copy_messages.clone();
+    let _copy_subscription = copy.listen(move |message| cclone.borrow_mut().push(message));
+    set.clear();
+    assert!(copy.is_empty());
+    assert_eq!(*copy_messages.borrow(), vec![MultiSetModifyMessage::Batch(vec![MultiSetModifyMessage::Clear(vec!["user1"]),
+        MultiSetModifyMessage::Delta("user1", -1)])]);
+}
+
+#[test]
+fn test_multi_set_distinct() {
+    let online = StreamingHashMultiSet::new();
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let mclone = messages.clone();
+    let _subscription = online.distinct().listen(move |message| mclone.borrow_mut().push(message));
+    // A user online on three devices is one online user.
+    online.insert("uid1");
+    online.insert("uid1");
+    online.add("uid1", 1);
+    assert_eq!(*messages.borrow(), vec![MultiSetModifyMessage::InsertOne("uid1")]);
+    online.remove("uid1");
+    online.add("uid1", -2);
+    online.insert("uid2");
+    online.apply(MultiSetModifyMessage::Replace { old: "uid2", new: "uid3" });
+    online.insert("uid3");
+    online.clear();
+    assert_eq!(*messages.borrow(), vec![MultiSetModifyMessage::InsertOne("uid1"), MultiSetModifyMessage::RemoveOne("uid1"),
+        MultiSetModifyMessage::InsertOne("uid2"), MultiSetModifyMessage::Replace { old: "uid2", new: "uid3" },
+        MultiSetModifyMessage::Clear(vec!["uid3"])]);
+}
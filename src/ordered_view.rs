@@ -0,0 +1,284 @@
+use std::{cell::{Cell, RefCell}, cmp::Ordering, hash::Hash, rc::Rc};
+
+use crate::{message_listeners::{MessageListeners, MessageListenersInterface}, multi_set::{MultiSetMessageListeners, MultiSetModifyMessage},
+    queryable_streaming_multi_map::QuerableStreamingMultiMap};
+#[cfg(test)]
+use crate::queryable_streaming_multi_map::StreamingHashMultiMapWithCount;
+
+/// A change of a list, where the indices refer to the list at the time the change is applied.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListModifyMessage<T> {
+    InsertAt(usize, T),
+    RemoveAt(usize),
+    /// Removes the item at the first index, then inserts it at the second one.
+    Move(usize, usize),
+    /// Overwrites the item at the index, sent after a `Move` when an item changed.
+    ReplaceAt(usize, T),
+    /// Changes that are applied in order.
+    Batch(Vec<ListModifyMessage<T>>)
+}
+
+impl<T> ListModifyMessage<T> {
+    pub fn from_messages(mut messages: Vec<ListModifyMessage<T>>)->Option<Self> {
+        match messages.len() {
+            0 => None,
+            1 => messages.pop(),
+            _ => Some(ListModifyMessage::Batch(messages))
+        }
+    }
+    /// Applies the change to a client side copy of the list.
+    pub fn apply_to(self, list: &mut Vec<T>) {
+        match self {
+            ListModifyMessage::InsertAt(index, item)=>list.insert(index, item),
+            ListModifyMessage::RemoveAt(index)=>{
+                list.remove(index);
+            },
+            ListModifyMessage::Move(from, to)=>{
+                let item=list.remove(from);
+                list.insert(to, item);
+            },
+            ListModifyMessage::ReplaceAt(index, item)=>list[index]=item,
+            ListModifyMessage::Batch(messages)=>for message in messages {
+                message.apply_to(list);
+            }
+        }
+    }
+}
+
+/// The items of a source sorted by a comparator, sent as positional changes, so that a
+///   client can keep an array up to date without sorting it.
+///
+/// Items that compare equal keep the order in which they arrived.
+pub struct OrderedView<'listener, T: Clone+'static> {
+    listeners: MessageListeners<'listener, ListModifyMessage<T>>,
+    // Shared with the windows of the view.
+    items: Rc<RefCell<Vec<T>>>
+}
+
+impl<'listener, T: Clone+'static> MessageListenersInterface<'listener, ListModifyMessage<T>> for OrderedView<'listener, T> {
+    fn listeners(&self)->&MessageListeners<'listener, ListModifyMessage<T>> {
+        &self.listeners
+    }
+}
+
+impl<'listener, T: Clone+'static> OrderedView<'listener, T> {
+    /// A snapshot of the sorted items.
+    pub fn items(&self)->Vec<T> {
+        self.items.borrow().clone()
+    }
+    pub fn get(&self, index: usize)->Option<T> {
+        self.items.borrow().get(index).cloned()
+    }
+    pub fn len(&self)->usize {
+        self.items.borrow().len()
+    }
+    pub fn is_empty(&self)->bool {
+        self.items.borrow().is_empty()
+    }
+}
+
+impl<'listener, T: Eq+Hash+Clone+'static> OrderedView<'listener, T> {
+    /// A live page of the view: the items at `[offset, offset+limit)`.  The window sends the
+    ///   items that scroll in as `InsertOne` and the ones that scroll out as `RemoveOne`.
+    ///
+    /// Every subscriber can create its own window and move it with `set_window`.
+    pub fn window(&self, offset: usize, limit: usize)->Rc<WindowView<'listener, T>> {
+        let r=Rc::new(WindowView { listeners: MultiSetMessageListeners::new(), items: self.items.clone(),
+            offset: Cell::new(offset), limit: Cell::new(limit), current: RefCell::new(Vec::new()) });
+        *r.current.borrow_mut()=r.visible();
+        r.listeners.depends_on(&self.listeners);
+        let weak=Rc::downgrade(&r);
+        r.listeners.hold(self.listen(move |_| {
+            if let Some(r)=weak.upgrade() {
+                r.update();
+            }
+        }));
+        r
+    }
+}
+
+/// A window of an `OrderedView`, see `OrderedView::window`.
+pub struct WindowView<'listener, T: Eq+Hash+Clone+'static> {
+    listeners: MultiSetMessageListeners<'listener, T>,
+    items: Rc<RefCell<Vec<T>>>,
+    offset: Cell<usize>,
+    limit: Cell<usize>,
+    // What the listeners were last told.
+    current: RefCell<Vec<T>>
+}
+
+impl<'listener, T: Eq+Hash+Clone+'static> MessageListenersInterface<'listener, MultiSetModifyMessage<T>> for WindowView<'listener, T> {
+    fn listeners(&self)->&MultiSetMessageListeners<'listener, T> {
+        &self.listeners
+    }
+}
+
+impl<'listener, T: Eq+Hash+Clone+'static> WindowView<'listener, T> {
+    /// The items of the window in order.
+    pub fn items(&self)->Vec<T> {
+        self.current.borrow().clone()
+    }
+    pub fn offset(&self)->usize {
+        self.offset.get()
+    }
+    pub fn limit(&self)->usize {
+        self.limit.get()
+    }
+    /// Moves or resizes the window, sending the items that scrolled in or out.
+    pub fn set_window(&self, offset: usize, limit: usize) {
+        self.offset.set(offset);
+        self.limit.set(limit);
+        self.update();
+    }
+    fn visible(&self)->Vec<T> {
+        let items=self.items.borrow();
+        let start=self.offset.get().min(items.len());
+        let end=self.offset.get().saturating_add(self.limit.get()).min(items.len());
+        items[start..end].to_vec()
+    }
+    fn update(&self) {
+        let new=self.visible();
+        let old=std::mem::replace(&mut *self.current.borrow_mut(), new.clone());
+        let messages=old.into_iter().map(MultiSetModifyMessage::RemoveOne)
+            .chain(new.into_iter().map(MultiSetModifyMessage::InsertOne));
+        if let Some(message)=MultiSetModifyMessage::from_consolidated(MultiSetModifyMessage::consolidate(messages)) {
+            self.listeners.send(message);
+        }
+    }
+}
+
+fn insert_position<T>(items: &[T], item: &T, compare: &impl Fn(&T, &T)->Ordering)->usize {
+    items.partition_point(|x| compare(x, item)!=Ordering::Greater)
+}
+
+fn position<T: PartialEq>(items: &[T], item: &T, compare: &impl Fn(&T, &T)->Ordering)->Option<usize> {
+    let start=items.partition_point(|x| compare(x, item)==Ordering::Less);
+    items[start..].iter().take_while(|x| compare(x, item)==Ordering::Equal)
+        .position(|x| x==item).map(|i| start+i)
+}
+
+fn apply_message<T: Clone+PartialEq>(items: &mut Vec<T>, message: MultiSetModifyMessage<T>,
+        compare: &impl Fn(&T, &T)->Ordering, out: &mut Vec<ListModifyMessage<T>>) {
+    match message {
+        MultiSetModifyMessage::Replace { old, new }=>match position(items, &old, compare) {
+            Some(from)=>{
+                items.remove(from);
+                let to=insert_position(items, &new, compare);
+                if from!=to {
+                    out.push(ListModifyMessage::Move(from, to));
+                }
+                if old!=new {
+                    out.push(ListModifyMessage::ReplaceAt(to, new.clone()));
+                }
+                items.insert(to, new);
+            },
+            None=>apply_message(items, MultiSetModifyMessage::InsertOne(new), compare, out)
+        },
+        MultiSetModifyMessage::Batch(messages)=>for message in messages {
+            apply_message(items, message, compare, out);
+        },
+        message=>for (item, diff) in message.into_weighted() {
+            for _ in 0..diff.unsigned_abs() {
+                if diff>0 {
+                    let index=insert_position(items, &item, compare);
+                    items.insert(index, item.clone());
+                    out.push(ListModifyMessage::InsertAt(index, item.clone()));
+                } else if let Some(index)=position(items, &item, compare) {
+                    items.remove(index);
+                    out.push(ListModifyMessage::RemoveAt(index));
+                }
+            }
+        }
+    }
+}
+
+/// The pairs of `source` sorted by `compare`, see `QuerableStreamingMultiMap::ordered_view`.
+pub fn ordered_view<'source, 'listener, K, V, Source>(source: &Source,
+        compare: impl Fn(&(K, V), &(K, V))->Ordering + 'listener)->Rc<OrderedView<'listener, (K, V)>>
+    where K: Eq+Hash+Clone+'static, V: Eq+Hash+Clone+'static, Source: QuerableStreamingMultiMap<'source, 'listener, K, V> {
+    let mut items: Vec<(K, V)>=source.weighted_items().into_iter()
+        .flat_map(|(item, count)| std::iter::repeat_n(item, count as usize)).collect();
+    items.sort_by(&compare);
+    let r=Rc::new(OrderedView { listeners: MessageListeners::new(), items: Rc::new(RefCell::new(items)) });
+    r.listeners.depends_on(source.listeners());
+    let weak=Rc::downgrade(&r);
+    r.listeners.hold(source.listen(move |message| {
+        if let Some(r)=weak.upgrade() {
+            let mut out=Vec::new();
+            apply_message(&mut r.items.borrow_mut(), message, &compare, &mut out);
+            if let Some(message)=ListModifyMessage::from_messages(out) {
+                r.listeners.send(message);
+            }
+        }
+    }));
+    r
+}
+
+#[test]
+fn test_ordered_view() {
+    let scores = StreamingHashMultiMapWithCount::new();
+    scores.insert("bob", 10);
+    let view = scores.ordered_view(|(_, a), (_, b)| b.cmp(a));
+    let client = Rc::new(RefCell::new(view.items()));
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let (cclone, mclone) = (client.clone(), messages.clone());
+    let _subscription = view.listen(move |message: ListModifyMessage<(&str, i32)>| {
+        message.clone().apply_to(&mut cclone.borrow_mut());
+        mclone.borrow_mut().push(message);
+    });
+    scores.insert("alice", 20);
+    scores.insert("carol", 5);
+    scores.set("carol", 30);
+    scores.remove("bob", 10);
+    assert_eq!(*messages.borrow(), vec![ListModifyMessage::InsertAt(0, ("alice", 20)), ListModifyMessage::InsertAt(2, ("carol", 5)),
+        ListModifyMessage::Batch(vec![ListModifyMessage::Move(2, 0), ListModifyMessage::ReplaceAt(0, ("carol", 30))]),
+        ListModifyMessage::RemoveAt(2)]);
+    assert_eq!(view.items(), vec![("carol", 30), ("alice", 20)]);
+    assert_eq!(*client.borrow(), view.items());
+}
+
+#[test]
+fn test_ordered_view_batch() {
+    let scores = StreamingHashMultiMapWithCount::new();
+    let view = scores.ordered_view(|a, b| a.cmp(b));
+    let client = Rc::new(RefCell::new(Vec::new()));
+    let cclone = client.clone();
+    let _subscription = view.listen(move |message: ListModifyMessage<(i32, i32)>| message.apply_to(&mut cclone.borrow_mut()));
+    scores.transaction(|tx| {
+        tx.insert(3, 0);
+        tx.insert(1, 0);
+        tx.insert(2, 0);
+    });
+    scores.clear();
+    scores.insert(2, 1);
+    assert_eq!(*client.borrow(), vec![(2, 1)]);
+    assert_eq!(view.len(), 1);
+}
+
+#[test]
+fn test_window() {
+    let followers = StreamingHashMultiMapWithCount::new();
+    for (name, since) in [("a", 1), ("b", 2), ("c", 3), ("d", 4), ("e", 5)] {
+        followers.insert(name, since);
+    }
+    let view = followers.ordered_view(|(_, a), (_, b)| a.cmp(b));
+    let page = view.window(2, 2);
+    let other_page = view.window(0, 2);
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let mclone = messages.clone();
+    let _subscription = page.listen(move |message| mclone.borrow_mut().push(message));
+    assert_eq!(page.items(), vec![("c", 3), ("d", 4)]);
+    followers.insert("z", 0);
+    assert_eq!(page.items(), vec![("b", 2), ("c", 3)]);
+    followers.remove("c", 3);
+    assert_eq!(page.items(), vec![("b", 2), ("d", 4)]);
+    followers.insert("f", 6);
+    page.set_window(4, 10);
+    assert_eq!(page.items(), vec![("e", 5), ("f", 6)]);
+    assert_eq!(other_page.items(), vec![("z", 0), ("a", 1)]);
+    assert_eq!(*messages.borrow(), vec![
+        MultiSetModifyMessage::Batch(vec![MultiSetModifyMessage::RemoveOne(("d", 4)), MultiSetModifyMessage::InsertOne(("b", 2))]),
+        MultiSetModifyMessage::Batch(vec![MultiSetModifyMessage::RemoveOne(("c", 3)), MultiSetModifyMessage::InsertOne(("d", 4))]),
+        MultiSetModifyMessage::Batch(vec![MultiSetModifyMessage::RemoveOne(("b", 2)), MultiSetModifyMessage::RemoveOne(("d", 4)),
+            MultiSetModifyMessage::InsertOne(("e", 5)), MultiSetModifyMessage::InsertOne(("f", 6))])]);
+}
@@ -1,10 +1,14 @@
-use std::{collections::{HashSet, HashMap}, cell::RefCell, rc::{Rc, Weak}, marker::PhantomData};
+use std::{collections::{BTreeMap, HashSet, HashMap}, cell::RefCell, cmp::Ordering, rc::Rc, marker::PhantomData, ops::{Add, Sub}};
 
-use crate::{multi_set::{MultiSetModifyMessage, MultiSetMessageListeners}, message_listeners::{MessageListenersInterface, MessageListeners}, rc_borrow::{RcBorrow, Borrow}};
+use chrono::{Duration, NaiveDateTime};
+
+use crate::{aggregate, multi_join::{Join3QuerableStreamingMultiMap, Join4QuerableStreamingMultiMap, Join5QuerableStreamingMultiMap}, ordered_view::{self, OrderedView}, time_window::{self, Clock}, multi_set::{MultiSetModifyMessage, MultiSetMessageListeners}, message_listeners::{MessageListenersInterface, MessageListeners, Subscription}, rc_borrow::{RcBorrow, Borrow}};
 use std::hash::Hash;
 
 pub trait QuerableStreamingMultiMapGetter<K:Eq+Hash+Clone + 'static,V:Eq+Hash+Clone+'static> {
     fn get(&self, key: &K)->HashSet<V>;
+    /// Returns every key value pair, used to fill operators that are attached late.
+    fn items(&self)->Vec<(K, V)>;
     fn get_one(&self, key: &K)->Option<V> {
         let set=self.get(key);
         if set.len()!=1 {
@@ -13,14 +17,32 @@ pub trait QuerableStreamingMultiMapGetter<K:Eq+Hash+Clone + 'static,V:Eq+Hash+Cl
             Some(set.iter().next().unwrap().clone())
         }
     }
+    /// The values of the key with their multiplicities.
+    fn counts(&self, key: &K)->HashMap<V, u64> {
+        self.get(key).into_iter().map(|v| (v, 1)).collect()
+    }
+    /// Returns every key value pair with its multiplicity.
+    fn weighted_items(&self)->Vec<((K, V), u64)> {
+        self.items().into_iter().map(|pair| (pair, 1)).collect()
+    }
 }
 
 
-/// An interface that represents key-value pairs where multiple values can be
-///   associated with a key, and a key-value pair can be inserted only once.
+/// An interface that represents a multiset of key-value pairs: multiple values can be
+///   associated with a key, and every pair has a multiplicity (how many times it was
+///   inserted and not removed).
+///
+/// `get` and `items` return the distinct values, `counts` and `weighted_items` the
+///   multiplicities.  The listeners get every change of a multiplicity, as `Delta` messages
+///   when it changes by more than one, and operators carry the weights through: a join
+///   multiplies the multiplicities of the joined values.
 ///
 ///  Requesting set of all values for a key is efficient, which makes joining
 ///   multiple QuerableStreamingMultiMaps on the same key efficient.
+///
+/// Operators can be attached at any time: `filter_item` and `join` read through to their
+///   sources, and `group_by` / `reversed` start with the current contents of the source.
+///   To build a map from future changes only, call them on `listeners()` instead.
 pub trait QuerableStreamingMultiMap<'source, 'listener, K:Eq+Hash+Clone + 'static,V:Eq+Hash+Clone+'static> :
      MessageListenersInterface<'listener, MultiSetModifyMessage<(K,V)>>  {
     type Getter : QuerableStreamingMultiMapGetter<K,V> + 'source + 'listener;
@@ -31,19 +53,29 @@ pub trait QuerableStreamingMultiMap<'source, 'listener, K:Eq+Hash+Clone + 'stati
     fn get_one(&self, key: &K)->Option<V> {
         self.getter().get_one(key)
     }
+    fn items(&self)->Vec<(K, V)> {
+        self.getter().items()
+    }
+    /// Every pair with its multiplicity, see `QuerableStreamingMultiMapGetter::weighted_items`.
+    fn weighted_items(&self)->Vec<((K, V), u64)> {
+        self.getter().weighted_items()
+    }
     fn filter_item<'last_source, Allow: Fn(K,V)->bool>(&'last_source self, allow: Allow)->
             FilterQuerableStreamingMultiMap<'source, 'listener, 'last_source, K, V, Self, Allow> {
         FilterQuerableStreamingMultiMap::new(self, allow)
     }
     fn filter_item_old<Allow: Fn(K,V)->bool+'listener>(&'listener self, allow: Allow)->
         Rc<MessageListeners<'listener, MultiSetModifyMessage<(K,V)>>> {
-        let f = move |m: &MultiSetModifyMessage<(K,V)>| {
-            match m {
-                MultiSetModifyMessage::InsertOne((k,v)) => allow(k.clone(), v.clone()),
-                MultiSetModifyMessage::RemoveOne((k,v)) => allow(k.clone(), v.clone())
+        let r = Rc::new(MessageListeners::new());
+        r.depends_on(self.listeners());
+        let weak=Rc::downgrade(&r);
+        r.hold(self.listen(move |message: MultiSetModifyMessage<(K,V)>| {
+            let filtered=message.filter_items(&|(k, v)| allow(k.clone(), v.clone()));
+            if let (Some(r), Some(filtered))=(weak.upgrade(), filtered) {
+                r.send(filtered);
             }
-        };
-        self.filter(f)
+        }));
+        r
     }
     fn map<T2:Clone+'static>(&'source self, f: impl Fn(K, V)->T2+'listener)->
             Rc<MultiSetMessageListeners<'listener, T2>> {
@@ -54,14 +86,113 @@ pub trait QuerableStreamingMultiMap<'source, 'listener, K:Eq+Hash+Clone + 'stati
             JoinQuerableStreamingMultiMap<'source, 'listener, 'last_source, K, V, V2, Self, Source2> {
         JoinQuerableStreamingMultiMap::new(self, other)
     }
+    /// Joins three inputs on the key, the values are flat tuples instead of nested pairs.
+    fn join3<'last_source, V2:Eq+Hash+Clone+'static, V3:Eq+Hash+Clone+'static,
+            Source2: QuerableStreamingMultiMap<'source, 'listener, K, V2>, Source3: QuerableStreamingMultiMap<'source, 'listener, K, V3>>(
+            &'last_source self, source2: &'last_source Source2, source3: &'last_source Source3)->
+            Join3QuerableStreamingMultiMap<'source, 'listener, 'last_source, K, V, V2, V3, Self, Source2, Source3> {
+        Join3QuerableStreamingMultiMap::new(self, source2, source3)
+    }
+    fn join4<'last_source, V2:Eq+Hash+Clone+'static, V3:Eq+Hash+Clone+'static, V4:Eq+Hash+Clone+'static,
+            Source2: QuerableStreamingMultiMap<'source, 'listener, K, V2>, Source3: QuerableStreamingMultiMap<'source, 'listener, K, V3>,
+            Source4: QuerableStreamingMultiMap<'source, 'listener, K, V4>>(
+            &'last_source self, source2: &'last_source Source2, source3: &'last_source Source3, source4: &'last_source Source4)->
+            Join4QuerableStreamingMultiMap<'source, 'listener, 'last_source, K, V, V2, V3, V4, Self, Source2, Source3, Source4> {
+        Join4QuerableStreamingMultiMap::new(self, source2, source3, source4)
+    }
+    fn join5<'last_source, V2:Eq+Hash+Clone+'static, V3:Eq+Hash+Clone+'static, V4:Eq+Hash+Clone+'static, V5:Eq+Hash+Clone+'static,
+            Source2: QuerableStreamingMultiMap<'source, 'listener, K, V2>, Source3: QuerableStreamingMultiMap<'source, 'listener, K, V3>,
+            Source4: QuerableStreamingMultiMap<'source, 'listener, K, V4>, Source5: QuerableStreamingMultiMap<'source, 'listener, K, V5>>(
+            &'last_source self, source2: &'last_source Source2, source3: &'last_source Source3, source4: &'last_source Source4,
+            source5: &'last_source Source5)->
+            Join5QuerableStreamingMultiMap<'source, 'listener, 'last_source, K, V, V2, V3, V4, V5, Self, Source2, Source3, Source4, Source5> {
+        Join5QuerableStreamingMultiMap::new(self, source2, source3, source4, source5)
+    }
+    /// Like `join`, but keys without values in `other` keep their values, paired with `None`.
+    fn left_join<'last_source,V2:Eq+Hash+Clone+'static, Source2: QuerableStreamingMultiMap<'source, 'listener, K, V2>>(
+            &'last_source self, other: &'last_source Source2)->
+            LeftJoinQuerableStreamingMultiMap<'source, 'listener, 'last_source, K, V, V2, Self, Source2> {
+        KeyJoinQuerableStreamingMultiMap::new(self, other, KeyJoinCombine { values: left_join_values, changes: left_join_changes })
+    }
+    /// The pairs whose key has values in `other`, without the values of `other`.
+    fn semi_join<'last_source,V2:Eq+Hash+Clone+'static, Source2: QuerableStreamingMultiMap<'source, 'listener, K, V2>>(
+            &'last_source self, other: &'last_source Source2)->
+            KeyJoinQuerableStreamingMultiMap<'source, 'listener, 'last_source, K, V, V2, V, Self, Source2> {
+        KeyJoinQuerableStreamingMultiMap::new(self, other, KeyJoinCombine { values: semi_join_values, changes: semi_join_changes })
+    }
+    /// The pairs whose key has no values in `other`.
+    fn anti_join<'last_source,V2:Eq+Hash+Clone+'static, Source2: QuerableStreamingMultiMap<'source, 'listener, K, V2>>(
+            &'last_source self, other: &'last_source Source2)->
+            KeyJoinQuerableStreamingMultiMap<'source, 'listener, 'last_source, K, V, V2, V, Self, Source2> {
+        KeyJoinQuerableStreamingMultiMap::new(self, other, KeyJoinCombine { values: anti_join_values, changes: anti_join_changes })
+    }
     
     fn reversed<'last_source>(&'last_source self)->Rc<StreamingHashMultiMapWithCount<'listener, V, K>> {
-        self.listeners().reversed()
+        self.group_by(|k, v| (v, k))
     }
     fn group_by<'last_source, K2: Eq+Hash+Clone+'static, V2: Eq+Hash+Clone+'static>(
             &'last_source self, f:impl Fn(K, V)->(K2, V2) + 'listener) ->
             Rc<StreamingHashMultiMapWithCount<'listener, K2,V2>> {
-        self.listeners().group_by(f)
+        let existing: Vec<((K2, V2), u64)>=self.weighted_items().into_iter().map(|((k, v), count)| (f(k, v), count)).collect();
+        let r=self.listeners().group_by(f);
+        r.transaction(|tx| for ((k, v), count) in existing {
+            tx.add(k, v, count as i64);
+        });
+        r
+    }
+    /// The number of values of every key (counting every copy of a value), updated
+    ///   incrementally; changes of a count are sent as `Replace` of the old count with the new one.
+    fn count_by_key(&self)->Rc<StreamingHashMultiMapWithCount<'listener, K, u64>> {
+        aggregate::count_by_key(self)
+    }
+    /// Reduces the values of every key, starting from `initial`, with `add` applied to inserted
+    ///   values and its inverse `retract` applied to removed values.  Both get the number of
+    ///   copies inserted or removed at once, so a weighted change is a single call.  Keys
+    ///   without values have no aggregate.
+    fn reduce_by_key<Out: Eq+Hash+Clone+'static>(&self, initial: Out, add: impl Fn(Out, &V, u64)->Out + 'listener,
+            retract: impl Fn(Out, &V, u64)->Out + 'listener)->Rc<StreamingHashMultiMapWithCount<'listener, K, Out>> {
+        aggregate::reduce_by_key(self, initial, add, retract)
+    }
+    /// Reduces the values of every key with a reducer that can't be inverted: `f` is called
+    ///   with all values of a key (read from this map) whenever they change.
+    fn reduce_by_key_recompute<'last_source, Out: Eq+Hash+Clone+'static>(&'last_source self,
+            f: impl Fn(&K, &HashSet<V>)->Out + 'listener)->aggregate::RecomputedByKey<'listener, 'last_source, K, Out, Self::Getter> {
+        aggregate::reduce_by_key_recompute(self, f)
+    }
+    /// Keeps the `k` values with the largest `sort_key` for every key.  When a value enters
+    ///   the window it is sent as a `Replace` of the value it pushes out, and when a value of
+    ///   the window is removed, as a `Replace` by the next best value.
+    fn top_k_by_key<S: Ord+'listener>(&self, k: usize, sort_key: impl Fn(&V)->S + 'listener)->
+            Rc<StreamingHashMultiMapWithCount<'listener, K, V>> {
+        aggregate::top_k_by_key(self, k, sort_key)
+    }
+    /// The sum of the values of every key, updated when values are inserted or removed.
+    fn sum_by_key(&self)->Rc<StreamingHashMultiMapWithCount<'listener, K, V>>
+            where V: Default+Add<Output=V>+Sub<Output=V> {
+        aggregate::sum_by_key(self)
+    }
+    /// The smallest value of every key; stays correct when the smallest value is removed.
+    fn min_by_key(&self)->Rc<StreamingHashMultiMapWithCount<'listener, K, V>> where V: Ord {
+        aggregate::min_by_key(self)
+    }
+    /// The largest value of every key; stays correct when the largest value is removed.
+    fn max_by_key(&self)->Rc<StreamingHashMultiMapWithCount<'listener, K, V>> where V: Ord {
+        aggregate::max_by_key(self)
+    }
+    /// The pairs sorted by `compare`, once per copy; listeners get the positions where pairs
+    ///   are inserted, removed or moved to.
+    fn ordered_view(&self, compare: impl Fn(&(K, V), &(K, V))->Ordering + 'listener)->Rc<OrderedView<'listener, (K, V)>> {
+        ordered_view::ordered_view(self, compare)
+    }
+    /// Assigns the pairs to time windows, starting with the current contents, see
+    ///   `MultiSetMessageListeners::sliding_window`.
+    fn sliding_window(&self, clock: &Clock<'listener>, time: impl Fn(&(K, V))->NaiveDateTime + 'listener, size: Duration,
+            slide: Duration)->Rc<StreamingHashMultiMapWithCount<'listener, NaiveDateTime, (K, V)>> {
+        time_window::sliding_window(self, self.weighted_items(), clock, time, size, slide)
+    }
+    fn tumbling_window(&self, clock: &Clock<'listener>, time: impl Fn(&(K, V))->NaiveDateTime + 'listener, size: Duration)->
+            Rc<StreamingHashMultiMapWithCount<'listener, NaiveDateTime, (K, V)>> {
+        self.sliding_window(clock, time, size, size)
     }
 }
 
@@ -81,14 +212,104 @@ pub trait QuerableStreamingMultiMap<'source, 'listener, K:Eq+Hash+Clone + 'stati
 /// 
 /// It also provides an interface that shows those pairs only once, thereby
 /// it's a useful datastructure for joining multiple data sets on the same key.
+///
+/// The listeners get every change of the multiplicity of a pair: inserts and removes for
+///   single changes and deltas for larger ones, so operators downstream can keep the counts.
 pub struct StreamingHashMultiMapWithCount<'listener, K: Eq+Hash+Clone+'static, V: Eq+Hash+Clone+'static> {
     listeners: MultiSetMessageListeners<'listener, (K, V)>,
-    data: RefCell<HashMap<K, HashMap<V, u64>>>
+    data: RefCell<HashMap<K, HashMap<V, u64>>>,
+    // Only maps created by `with_clock` have a time to live.
+    expiry: Option<RefCell<Expiry<K, V>>>
+}
+
+/// The deadlines of the pairs that have a time to live.
+struct Expiry<K, V> {
+    ttl: Option<Duration>,
+    // The time of the clock of the map.
+    now: NaiveDateTime,
+    deadlines: HashMap<(K, V), NaiveDateTime>,
+    // The pairs of `deadlines` by deadline.
+    queue: BTreeMap<NaiveDateTime, Vec<(K, V)>>
+}
+
+impl<K: Eq+Hash+Clone, V: Eq+Hash+Clone> Expiry<K, V> {
+    fn set_deadline(&mut self, key: &K, value: &V, deadline: NaiveDateTime) {
+        let pair=(key.clone(), value.clone());
+        if let Some(old)=self.deadlines.insert(pair.clone(), deadline) {
+            self.unqueue(&pair, old);
+        }
+        self.queue.entry(deadline).or_default().push(pair);
+    }
+    /// Restarts the default time to live of a pair that was written, or keeps it until it's
+    ///   removed if the map has no time to live.
+    fn touch(&mut self, key: &K, value: &V) {
+        match self.ttl {
+            Some(ttl) => self.set_deadline(key, value, self.now+ttl),
+            None => self.forget(key, value)
+        }
+    }
+    fn forget(&mut self, key: &K, value: &V) {
+        if self.deadlines.is_empty() {
+            return;
+        }
+        let pair=(key.clone(), value.clone());
+        if let Some(old)=self.deadlines.remove(&pair) {
+            self.unqueue(&pair, old);
+        }
+    }
+    fn unqueue(&mut self, pair: &(K, V), deadline: NaiveDateTime) {
+        let pairs=self.queue.get_mut(&deadline).unwrap();
+        pairs.retain(|p| p!=pair);
+        if pairs.is_empty() {
+            self.queue.remove(&deadline);
+        }
+    }
+    fn take_expired(&mut self, now: NaiveDateTime)->Vec<(K, V)> {
+        let mut r=Vec::new();
+        while self.queue.first_key_value().is_some_and(|(deadline, _)| *deadline<=now) {
+            let (_, pairs)=self.queue.pop_first().unwrap();
+            for pair in &pairs {
+                self.deadlines.remove(pair);
+            }
+            r.extend(pairs);
+        }
+        r
+    }
 }
 
 impl<'a, K: Eq+Hash+Clone+'static, V: Eq+Hash+Clone+'static> StreamingHashMultiMapWithCount<'a, K, V> {
     pub fn new()->Self {
-        Self {listeners: MultiSetMessageListeners::new(), data: RefCell::new(HashMap::new())}
+        Self {listeners: MultiSetMessageListeners::new(), data: RefCell::new(HashMap::new()), expiry: None}
+    }
+    /// A map whose time to live (see `set_ttl`) is measured by `clock`: the pairs are removed
+    ///   when the clock moves past their deadline.
+    pub fn with_clock(clock: &Clock<'a>)->Rc<Self> {
+        let expiry=Expiry { ttl: None, now: clock.now(), deadlines: HashMap::new(), queue: BTreeMap::new() };
+        let r=Rc::new(Self { expiry: Some(RefCell::new(expiry)), ..Self::new() });
+        r.listeners().depends_on(clock.listeners());
+        let weak=Rc::downgrade(&r);
+        r.listeners().hold(clock.listen(move |time| {
+            if let Some(r)=weak.upgrade() {
+                r.expiry().borrow_mut().now=time;
+                r.expire_until(time);
+            }
+        }));
+        r
+    }
+
+    fn expiry(&self)->&RefCell<Expiry<K, V>> {
+        self.expiry.as_ref().expect("a time to live needs a map created by with_clock")
+    }
+
+    /// How many times the pair was inserted (and not removed).
+    pub fn count(&self, key: &K, value: &V)->u64 {
+        self.data.borrow().get(key).and_then(|values| values.get(value)).copied().unwrap_or(0)
+    }
+}
+
+impl<'a, K: Eq+Hash+Clone+'static, V: Eq+Hash+Clone+'static> Default for StreamingHashMultiMapWithCount<'a, K, V> {
+    fn default()->Self {
+        Self::new()
     }
 }
 
@@ -96,17 +317,20 @@ impl<K: Eq+Hash+Clone + 'static, V: Eq+Hash+Clone+'static>
     QuerableStreamingMultiMapGetter<K,V> for RefCell<HashMap<K, HashMap<V, u64>>> {
     fn get(&self, key: &K)->HashSet<V> {
         let data = self.borrow();
-        let values=data.get(key);
-        if values.is_none() {
-            return HashSet::new();
-        } else {
-            let mut r=HashSet::new();
-            for key in values.unwrap().keys() {
-                r.insert(key.clone());
-            }
-            return r;
+        match data.get(key) {
+            None => HashSet::new(),
+            Some(values) => values.keys().cloned().collect()
         }
     }
+    fn items(&self)->Vec<(K, V)> {
+        self.borrow().iter().flat_map(|(k, values)| values.keys().map(|v| (k.clone(), v.clone()))).collect()
+    }
+    fn counts(&self, key: &K)->HashMap<V, u64> {
+        self.borrow().get(key).cloned().unwrap_or_default()
+    }
+    fn weighted_items(&self)->Vec<((K, V), u64)> {
+        self.borrow().iter().flat_map(|(k, values)| values.iter().map(|(v, count)| ((k.clone(), v.clone()), *count))).collect()
+    }
 }
 
 impl<'listener, K: Eq+Hash+Clone + 'static, V: Eq+Hash+Clone+'static>
@@ -117,25 +341,13 @@ impl<'listener, K: Eq+Hash+Clone + 'static, V: Eq+Hash+Clone+'static>
         &self.data
     }
     fn get(&self, key: &K)->HashSet<V> {
-        let data = self.data.borrow();
-        let values=data.get(key);
-        if values.is_none() {
-            return HashSet::new();
-        } else {
-            let mut r=HashSet::new();
-            for key in values.unwrap().keys() {
-                r.insert(key.clone());
-            }
-            return r;
-        }
+        self.data.get(key)
     }
     fn get_one(&self, key: &K)->Option<V> {
         let data = self.data.borrow();
-        let values=data.get(key);
-        if values.is_none() || values.unwrap().len()!=1 {
-            return None;
-        } else {
-            return values.unwrap().keys().next().map(|v| v.clone());
+        match data.get(key) {
+            Some(values) if values.len()==1 => values.keys().next().cloned(),
+            _ => None
         }
     }
 }
@@ -150,52 +362,274 @@ impl <'a, K: Eq+Hash+Clone + 'static, V: Eq+Hash+Clone+'static>
 // impl<'a, K: Eq+Hash+Clone+'static, V: Eq+Hash+Clone+'static> JoinMultiMap<'a, K, V> for StreamingHashMultiMapWithCount<'a, K, V> {}
 
 impl<'a, K: Eq+Hash+Clone+'static, V: Eq+Hash+Clone+'static> StreamingHashMultiMapWithCount<'a, K, V> {
+    /// Inserts a key value pair and notifies the listeners.
+    ///
+    /// The data is not borrowed while the listeners run, so listeners may query or modify
+    ///   this map.
     pub fn insert(&self, key: K, value: V) {
-        let mut data = self.data.borrow_mut();
-        let value_edit=data.get_mut(&key);
-        if value_edit.is_none() {
-            let mut value_hash_map=HashMap::new();
-            value_hash_map.insert(value.clone(), 1);
-            self.listeners.send(MultiSetModifyMessage::InsertOne((key.clone(), value.clone())));
-           data.insert(key, value_hash_map);
-        } else {
-            let value_hash_map=value_edit.unwrap();
-            let value_value = value_hash_map.get_mut(&value);
-            if value_value.is_none() {
-                value_hash_map.insert(value.clone(), 1);
-                self.listeners.send(MultiSetModifyMessage::InsertOne((key, value)));
-            } else {
-                *value_value.unwrap()+=1;
-            }
+        *self.data.borrow_mut().entry(key.clone()).or_default().entry(value.clone()).or_insert(0)+=1;
+        if let Some(expiry)=&self.expiry {
+            expiry.borrow_mut().touch(&key, &value);
         }
+        self.listeners.send(MultiSetModifyMessage::InsertOne((key, value)));
     }
     pub fn remove(&self, key: K, value: V)->bool {
-        let mut data = self.data.borrow_mut();
-        let value_edit=data.get_mut(&key);
-        if value_edit.is_none() {
-            return false;
-        } else {
-            let value_hash_map=value_edit.unwrap();
-            let value_value = value_hash_map.get_mut(&value);
-            if value_value.is_none() {
+        let removed = {
+            let mut data = self.data.borrow_mut();
+            let Some(value_hash_map)=data.get_mut(&key) else {
                 return false;
+            };
+            let Some(count)=value_hash_map.get_mut(&value) else {
+                return false;
+            };
+            *count-=1;
+            if *count==0 {
+                value_hash_map.remove(&value);
+                if value_hash_map.is_empty() {
+                    data.remove(&key);
+                }
+                true
             } else {
-                let vvu=value_value.unwrap();
-                *vvu-=1;
-                if *vvu==0 {
-                    value_hash_map.remove(&value);
-                    if value_hash_map.is_empty() {
-                        data.remove(&key);
+                false
+            }
+        };
+        if let (true, Some(expiry))=(removed, &self.expiry) {
+            expiry.borrow_mut().forget(&key, &value);
+        }
+        self.listeners.send(MultiSetModifyMessage::RemoveOne((key, value)));
+        true
+    }
+    /// Changes the multiplicity of the pair by `diff`, without going below 0.
+    pub fn add(&self, key: K, value: V, diff: i64) {
+        self.transaction(|tx| tx.add(key, value, diff));
+    }
+    /// Removes every pair, sending a `Clear` message, see `MultiSetModifyMessage::remove_all`.
+    pub fn clear(&self) {
+        let data=std::mem::take(&mut *self.data.borrow_mut());
+        self.send_cleared(data.into_iter().flat_map(|(k, values)| values.into_iter().map(move |(v, count)| ((k.clone(), v), count))),
+            MultiSetModifyMessage::Clear);
+    }
+    /// Removes every value of the key, sending a `ClearKey` message.  Returns false if the key
+    ///   had no values.
+    pub fn remove_key(&self, key: &K)->bool {
+        let values=self.data.borrow_mut().remove(key);
+        let Some(values)=values else {
+            return false;
+        };
+        self.send_cleared(values.into_iter().map(|(v, count)| ((key.clone(), v), count)), MultiSetModifyMessage::ClearKey);
+        true
+    }
+    fn send_cleared(&self, removed: impl Iterator<Item=((K, V), u64)>,
+            message: impl FnOnce(Vec<(K, V)>)->MultiSetModifyMessage<(K, V)>) {
+        let removed: Vec<_>=removed.collect();
+        if let Some(expiry)=&self.expiry {
+            let mut expiry=expiry.borrow_mut();
+            for ((k, v), _) in &removed {
+                expiry.forget(k, v);
+            }
+        }
+        if let Some(message)=MultiSetModifyMessage::remove_all(removed, message) {
+            self.listeners.send(message);
+        }
+    }
+    /// Removes the pairs for which `f` returns false, in one transaction.
+    pub fn retain(&self, f: impl Fn(&K, &V)->bool) {
+        let items=self.items();
+        self.transaction(|tx| for (k, v) in items {
+            if !f(&k, &v) {
+                tx.remove_all(k, v);
+            }
+        });
+    }
+    /// Applies a message to the map, deltas and batches are applied in a transaction.  A
+    ///   `Clear` removes only the listed pairs, see `MultiSetModifyMessage::Clear`.
+    pub fn apply(&self, message: MultiSetModifyMessage<(K, V)>) {
+        match message {
+            MultiSetModifyMessage::InsertOne((k, v))=>self.insert(k, v),
+            MultiSetModifyMessage::RemoveOne((k, v))=>{self.remove(k, v);},
+            message=>self.transaction(|tx| tx.apply(message))
+        }
+    }
+
+    /// Runs `f` with a transaction that buffers inserts and removes, then applies them at once.
+    ///
+    /// The listeners get a single (batch) message with the changes of the multiplicities made by
+    ///   the whole transaction, so a pair that is removed and inserted again isn't sent at all.
+    ///   Replacements (from `set` or applied replace messages) are sent as `Replace`.
+    pub fn transaction<R>(&self, f: impl FnOnce(&mut Transaction<'_, 'a, K, V>)->R)->R {
+        let mut tx=Transaction { map: self, counts: HashMap::new(), order: Vec::new(), replaces: Vec::new(), cleared: false };
+        let r=f(&mut tx);
+        let mut messages=Vec::new();
+        {
+            let mut data=self.data.borrow_mut();
+            let mut expiry=self.expiry.as_ref().map(|expiry| expiry.borrow_mut());
+            for (key, value) in tx.order {
+                let count=tx.counts[&(key.clone(), value.clone())];
+                let values=data.entry(key.clone()).or_default();
+                let old=if count==0 { values.remove(&value) } else { values.insert(value.clone(), count) };
+                // Only writing the pair again restarts its time to live, not removing copies of it.
+                if let Some(expiry)=&mut expiry {
+                    if count==0 {
+                        expiry.forget(&key, &value);
+                    } else if count>old.unwrap_or(0) {
+                        expiry.touch(&key, &value);
                     }
-                    self.listeners.send(MultiSetModifyMessage::RemoveOne((key, value)));
                 }
-                return true;
+                if values.is_empty() {
+                    data.remove(&key);
+                }
+                let diff=count as i64-old.unwrap_or(0) as i64;
+                if diff!=0 {
+                    messages.push(MultiSetModifyMessage::weighted((key, value), diff));
+                }
+            }
+            if tx.cleared && data.is_empty() {
+                messages=MultiSetModifyMessage::group_removes(messages, MultiSetModifyMessage::Clear);
             }
         }
+        let messages=MultiSetModifyMessage::pair_replaces(messages, &tx.replaces);
+        if let Some(message)=MultiSetModifyMessage::from_messages(messages) {
+            self.listeners.send(message);
+        }
+        r
     }
+    /// Replaces all values of the key with the value in one transaction.
     pub fn set(&self, key: K, value: V) {
-        let vs=self.get(&key);
-        for v in vs {
+        self.transaction(|tx| tx.set(key, value));
+    }
+
+    /// Sets the time to live of the pairs written from now on (by any method), or None to keep
+    ///   them until they are removed.  Writing a pair again restarts its time to live.
+    ///
+    /// Panics for a map that wasn't created by `with_clock`.
+    pub fn set_ttl(&self, ttl: Option<Duration>) {
+        self.expiry().borrow_mut().ttl=ttl;
+    }
+    /// Inserts a pair that expires after `ttl`, overriding the time to live of the map.
+    ///
+    /// Panics for a map that wasn't created by `with_clock`.
+    pub fn insert_with_ttl(&self, key: K, value: V, ttl: Duration) {
+        let deadline=self.expiry().borrow().now+ttl;
+        self.insert(key.clone(), value.clone());
+        self.expiry().borrow_mut().set_deadline(&key, &value, deadline);
+    }
+    /// Removes the pairs whose time to live ended at or before `now`, in one transaction, so
+    ///   the listeners get ordinary remove messages.  Returns the number of expired pairs.
+    ///
+    /// The clock of the map calls it when it moves.
+    pub fn expire_until(&self, now: NaiveDateTime)->usize {
+        let Some(expiry)=&self.expiry else {
+            return 0;
+        };
+        let expired=expiry.borrow_mut().take_expired(now);
+        let n=expired.len();
+        if n>0 {
+            self.transaction(|tx| for (k, v) in expired {
+                tx.remove_all(k, v);
+            });
+        }
+        n
+    }
+}
+
+/// Changes buffered by `StreamingHashMultiMapWithCount::transaction`.
+///
+/// The changes have to go through the transaction, `get` shows the map with the buffered
+///   changes applied.
+pub struct Transaction<'t, 'a, K: Eq+Hash+Clone+'static, V: Eq+Hash+Clone+'static> {
+    map: &'t StreamingHashMultiMapWithCount<'a, K, V>,
+    counts: HashMap<(K, V), u64>,
+    order: Vec<(K, V)>,
+    replaces: Vec<((K, V), (K, V))>,
+    /// Whether the map was cleared, so emptying it is sent as a `Clear`.
+    cleared: bool
+}
+
+impl<'t, 'a, K: Eq+Hash+Clone+'static, V: Eq+Hash+Clone+'static> Transaction<'t, 'a, K, V> {
+    fn count(&mut self, key: &K, value: &V)->&mut u64 {
+        let pair=(key.clone(), value.clone());
+        if !self.counts.contains_key(&pair) {
+            let count=self.map.data.borrow().get(key).and_then(|values| values.get(value)).copied().unwrap_or(0);
+            self.order.push(pair.clone());
+            self.counts.insert(pair.clone(), count);
+        }
+        self.counts.get_mut(&pair).unwrap()
+    }
+    pub fn insert(&mut self, key: K, value: V) {
+        *self.count(&key, &value)+=1;
+    }
+    pub fn remove(&mut self, key: K, value: V)->bool {
+        let count=self.count(&key, &value);
+        if *count==0 {
+            return false;
+        }
+        *count-=1;
+        true
+    }
+    /// Changes the multiplicity of the pair by `diff`, without going below 0.
+    pub fn add(&mut self, key: K, value: V, diff: i64) {
+        let count=self.count(&key, &value);
+        *count=(*count as i64+diff).max(0) as u64;
+    }
+    fn remove_all(&mut self, key: K, value: V) {
+        *self.count(&key, &value)=0;
+    }
+    /// Removes every value of the key.
+    pub fn remove_key(&mut self, key: &K) {
+        for v in self.get(key) {
+            self.remove_all(key.clone(), v);
+        }
+    }
+    /// Removes every pair; inserts after it in the same transaction are kept, so the map can
+    ///   be reloaded with only the differences sent to the listeners.
+    pub fn clear(&mut self) {
+        self.cleared=true;
+        for (k, v) in self.map.items() {
+            self.remove_all(k, v);
+        }
+        for count in self.counts.values_mut() {
+            *count=0;
+        }
+    }
+    /// Applies a message, a `Clear` removes only the listed pairs (see `clear` for removing
+    ///   every pair).
+    pub fn apply(&mut self, message: MultiSetModifyMessage<(K, V)>) {
+        self.cleared|=matches!(message.first(), Some(MultiSetModifyMessage::Clear(_)));
+        self.replaces.extend(message.replaces());
+        for ((k, v), diff) in message.into_weighted() {
+            self.add(k, v, diff);
+        }
+    }
+    pub fn get(&self, key: &K)->HashSet<V> {
+        let mut r=self.map.get(key);
+        for ((k, v), count) in &self.counts {
+            if k==key {
+                if *count>0 {
+                    r.insert(v.clone());
+                } else {
+                    r.remove(v);
+                }
+            }
+        }
+        r
+    }
+    /// Replaces one copy of the `old` value of the key with `new`, sent as a `Replace`.  If
+    ///   the key has no `old` value, `new` is only inserted.
+    pub fn replace(&mut self, key: K, old: V, new: V) {
+        if self.remove(key.clone(), old.clone()) {
+            self.replaces.push(((key.clone(), old), (key.clone(), new.clone())));
+        }
+        self.insert(key, new);
+    }
+    /// Replaces all values of the key with the value; if the key had a single other value,
+    ///   the change is sent as a `Replace`.
+    pub fn set(&mut self, key: K, value: V) {
+        let old=self.get(&key);
+        if old.len()==1 && !old.contains(&value) {
+            let old_value=old.iter().next().unwrap().clone();
+            self.replaces.push(((key.clone(), old_value), (key.clone(), value.clone())));
+        }
+        for v in old {
             self.remove(key.clone(), v);
         }
         self.insert(key, value);
@@ -205,11 +639,12 @@ impl<'a, K: Eq+Hash+Clone+'static, V: Eq+Hash+Clone+'static> StreamingHashMultiM
 pub struct FilterQuerableStreamingMultiMap<'source, 'listener, 'last_source, K:Eq+Hash+Clone+'static,V:Eq+Hash+Clone+'static,
     Source: QuerableStreamingMultiMap<'source, 'listener, K,V>,
     Allow: Fn(K, V)->bool + 'source + 'listener> {
+    _subscription: Subscription<'listener>,
     source: &'last_source Source,
     listeners:  Rc<MultiSetMessageListeners<'listener, (K, V)>>,
     allow: Rc<Allow>,
     getter: FilterQuerableStreamingMultiMapGetter<K,V, Source::Getter, Allow>,
-    source_getter: RcBorrow<'last_source, Source::Getter>
+    _source_getter: RcBorrow<'last_source, Source::Getter>
 }
 
 pub struct FilterQuerableStreamingMultiMapGetter<K:Eq+Hash+Clone+'static,V:Eq+Hash+Clone+'static,
@@ -220,9 +655,9 @@ pub struct FilterQuerableStreamingMultiMapGetter<K:Eq+Hash+Clone+'static,V:Eq+Ha
     phantom_data: PhantomData<(K,V)>
 }
 
-impl <'source, 'listener, K:Eq+Hash+Clone,V:Eq+Hash+Clone,
+impl <K:Eq+Hash+Clone,V:Eq+Hash+Clone,
         Getter: QuerableStreamingMultiMapGetter<K,V>,
-        Allow: Fn(K, V)->bool + 'source + 'listener>
+        Allow: Fn(K, V)->bool>
     QuerableStreamingMultiMapGetter<K,V>
             for FilterQuerableStreamingMultiMapGetter<K,V, Getter, Allow> {
         fn get(&self, key: &K)->HashSet<V> {
@@ -230,6 +665,15 @@ impl <'source, 'listener, K:Eq+Hash+Clone,V:Eq+Hash+Clone,
         let allow=&self.allow;
         values.into_iter().filter(|v| allow(key.clone(), v.clone())).collect()
     }
+    fn items(&self)->Vec<(K, V)> {
+        self.source.items().into_iter().filter(|(k, v)| (self.allow)(k.clone(), v.clone())).collect()
+    }
+    fn counts(&self, key: &K)->HashMap<V, u64> {
+        self.source.counts(key).into_iter().filter(|(v, _)| (self.allow)(key.clone(), v.clone())).collect()
+    }
+    fn weighted_items(&self)->Vec<((K, V), u64)> {
+        self.source.weighted_items().into_iter().filter(|((k, v), _)| (self.allow)(k.clone(), v.clone())).collect()
+    }
 }
 
 impl<'source, 'listener, 'last_source, K:Eq+Hash+Clone,V:Eq+Hash+Clone,
@@ -239,35 +683,27 @@ impl<'source, 'listener, 'last_source, K:Eq+Hash+Clone,V:Eq+Hash+Clone,
     pub fn new(source: &'last_source Source, allow: Allow)->Self {
         let source_getter=RcBorrow::new(source.getter());
         let allow=Rc::new(allow);
-        let r = Self {
+        let listeners=Rc::new(MultiSetMessageListeners::new());
+        listeners.depends_on(source.listeners());
+        let lclone=listeners.clone();
+        let aclone=allow.clone();
+        let subscription=source.listeners().listen(move |message: MultiSetModifyMessage<(K,V)>| {
+            if let Some(message)=message.filter_items(&|(key, value)| (aclone)(key.clone(), value.clone())) {
+                lclone.send(message);
+            }
+        });
+        Self {
+            _subscription: subscription,
             allow: allow.clone(),
-            listeners: Rc::new(MultiSetMessageListeners::new()),
+            listeners,
             source,
             getter: FilterQuerableStreamingMultiMapGetter {
                 source: source_getter.get(),
                 allow,
                 phantom_data: PhantomData
             },
-            source_getter
-        };
-            
-        let lclone=r.listeners.clone();
-        let aclone=r.allow.clone();
-        source.listeners().listen(move |message| {
-            match &message {
-                MultiSetModifyMessage::InsertOne((key, value))=> {
-                    if (aclone)(key.clone(), value.clone()) {
-                        lclone.send(message.clone());
-                    }
-                },
-                MultiSetModifyMessage::RemoveOne((key, value))=>{
-                    if (aclone)(key.clone(), value.clone()) {
-                        lclone.send(message.clone());
-                    }
-                }
-            }
-        }); 
-        r
+            _source_getter: source_getter
+        }
     }
 }
 
@@ -295,7 +731,7 @@ impl<'source, 'listener, 'last_source, K:Eq+Hash+Clone,V:Eq+Hash+Clone,
                     r.insert(v.clone());
                 }
             }
-            return r;
+            r
         }
 }
 
@@ -316,16 +752,114 @@ impl <'source, 'listener, 'last_source, K:Eq+Hash+Clone,V:Eq+Hash+Clone,
 pub struct JoinQuerableStreamingMultiMap<'source, 'listener, 'last_source, K:Eq+Hash+Clone+'static,V:Eq+Hash+Clone+'static, V2:Eq+Hash+Clone+'static,
     Source: QuerableStreamingMultiMap<'source, 'listener, K,V>,
     Source2: QuerableStreamingMultiMap<'source, 'listener, K,V2>> {
-    source: RcBorrow<'last_source, Source>,
-    source2: RcBorrow<'last_source, Source2>,
-    listeners:  Rc<MultiSetMessageListeners<'listener, (K, (V, V2))>>,
-    source_cancel_index: Option<usize>,
-    source2_cancel_index: Option<usize>,
+    _source_subscription: Subscription<'listener>,
+    _source2_subscription: Subscription<'listener>,
+    // The state holds clones of the getters, so it has to be dropped before the RcBorrows.
+    state: SharedJoinState<'listener, K, V, V2, Source::Getter, Source2::Getter>,
     getter: JoinQuerableStreamingMultiMapGetter<K,V,V2, Source::Getter, Source2::Getter>,
-    source_getter: RcBorrow<'last_source, Source::Getter>,
-    source2_getter: RcBorrow<'last_source, Source2::Getter>,
+    _source_getter: RcBorrow<'last_source, Source::Getter>,
+    _source2_getter: RcBorrow<'last_source, Source2::Getter>,
+}
+
+type SharedJoinState<'listener, K, V, V2, SourceGetter, SourceGetter2>=Rc<JoinState<'listener, K, V, V2, SourceGetter, SourceGetter2>>;
+
+/// Changes of the join inputs that arrived during the current update.
+struct JoinPending<K:Clone, V:Clone, V2:Clone> {
+    source: Vec<MultiSetModifyMessage<(K, V)>>,
+    source2: Vec<MultiSetModifyMessage<(K, V2)>>,
+    scheduled: bool
+}
+
+struct JoinState<'listener, K:Eq+Hash+Clone+'static, V:Eq+Hash+Clone+'static, V2:Eq+Hash+Clone+'static, SourceGetter, SourceGetter2> {
+    listeners: Rc<MultiSetMessageListeners<'listener, (K, (V, V2))>>,
+    source: Rc<Borrow<SourceGetter>>,
+    source2: Rc<Borrow<SourceGetter2>>,
+    pending: RefCell<JoinPending<K, V, V2>>
 }
 
+impl<'listener, K:Eq+Hash+Clone+'static, V:Eq+Hash+Clone+'static, V2:Eq+Hash+Clone+'static,
+    SourceGetter: QuerableStreamingMultiMapGetter<K,V>+'listener,
+    SourceGetter2: QuerableStreamingMultiMapGetter<K,V2>+'listener>
+    JoinState<'listener, K, V, V2, SourceGetter, SourceGetter2> {
+    /// Buffers changes of an input; the join runs once per update at its own rank, when both
+    ///   inputs are up to date.
+    fn push(self: &Rc<Self>, f: impl FnOnce(&mut JoinPending<K, V, V2>)) {
+        let schedule={
+            let mut pending=self.pending.borrow_mut();
+            f(&mut pending);
+            !std::mem::replace(&mut pending.scheduled, true)
+        };
+        if schedule {
+            let weak=Rc::downgrade(self);
+            self.listeners.schedule(move || if let Some(state)=weak.upgrade() { state.flush() });
+        }
+    }
+
+    /// Sends the changes of the join for the buffered input changes:
+    ///   changes of the first input joined with the old values of the second input, plus
+    ///   the new values of the first input joined with changes of the second input.  The
+    ///   weight of a joined pair is the product of the weights of its parts.
+    fn flush(&self) {
+        let (source, source2)={
+            let mut pending=self.pending.borrow_mut();
+            pending.scheduled=false;
+            (std::mem::take(&mut pending.source), std::mem::take(&mut pending.source2))
+        };
+        // Clearing an input clears the join (or the key of the input).
+        let cleared=source.iter().any(|message| matches!(message.first(), Some(MultiSetModifyMessage::Clear(_)))) ||
+            source2.iter().any(|message| matches!(message.first(), Some(MultiSetModifyMessage::Clear(_))));
+        let key_cleared=source.iter().all(|message| matches!(message.first(), Some(MultiSetModifyMessage::ClearKey(_)))) &&
+            source2.iter().all(|message| matches!(message.first(), Some(MultiSetModifyMessage::ClearKey(_))));
+        // A replace that keeps the key replaces the joined pairs too.
+        let mut replaces=Vec::new();
+        for ((key, old), (new_key, new)) in source.iter().flat_map(|message| message.replaces()) {
+            if key==new_key {
+                for value2 in self.source2.get(&key) {
+                    replaces.push(((key.clone(), (old.clone(), value2.clone())), (key.clone(), (new.clone(), value2))));
+                }
+            }
+        }
+        for ((key, old2), (new_key, new2)) in source2.iter().flat_map(|message| message.replaces()) {
+            if key==new_key {
+                for value in self.source.get(&key) {
+                    replaces.push(((key.clone(), (value.clone(), old2.clone())), (key.clone(), (value, new2.clone()))));
+                }
+            }
+        }
+        let changes=MultiSetModifyMessage::consolidate(source);
+        let changes2=MultiSetModifyMessage::consolidate(source2);
+        let mut changes2_by_key: HashMap<K, Vec<(V2, i64)>>=HashMap::new();
+        for ((key, value2), diff) in &changes2 {
+            changes2_by_key.entry(key.clone()).or_default().push((value2.clone(), *diff));
+        }
+        let mut joined=Vec::new();
+        for ((key, value), diff) in changes {
+            let old_counts2=old_counts(self.source2.counts(&key), changes2_by_key.get(&key).map(Vec::as_slice).unwrap_or(&[]));
+            for (value2, count2) in old_counts2 {
+                joined.push(((key.clone(), (value.clone(), value2)), diff*count2 as i64));
+            }
+        }
+        for ((key, value2), diff2) in changes2 {
+            for (value, count) in self.source.counts(&key) {
+                joined.push(((key.clone(), (value, value2.clone())), count as i64*diff2));
+            }
+        }
+        let joined=MultiSetModifyMessage::consolidate(MultiSetModifyMessage::from_consolidated(joined));
+        let messages=MultiSetModifyMessage::from_consolidated(joined).map(|message| message.into_messages()).unwrap_or_default();
+        let messages=MultiSetModifyMessage::pair_replaces(messages, &replaces);
+        let messages=if cleared {
+            MultiSetModifyMessage::group_removes(messages, MultiSetModifyMessage::Clear)
+        } else if key_cleared && messages.windows(2).all(|pair| matches!(pair,
+                [MultiSetModifyMessage::RemoveOne((key, _)), MultiSetModifyMessage::RemoveOne((key2, _))] if key==key2)) {
+            MultiSetModifyMessage::group_removes(messages, MultiSetModifyMessage::ClearKey)
+        } else {
+            messages
+        };
+        if let Some(message)=MultiSetModifyMessage::from_messages(messages) {
+            self.listeners.send(message);
+        }
+    }
+}
 
 pub struct JoinQuerableStreamingMultiMapGetter<K:Eq+Hash+Clone+'static
     ,V:Eq+Hash+Clone+'static, V2:Eq+Hash+Clone+'static,
@@ -337,7 +871,7 @@ pub struct JoinQuerableStreamingMultiMapGetter<K:Eq+Hash+Clone+'static
 
 }
 
-impl <'source, K:Eq+Hash+Clone,V:Eq+Hash+Clone, V2:Eq+Hash+Clone,
+impl <K:Eq+Hash+Clone,V:Eq+Hash+Clone, V2:Eq+Hash+Clone,
     Getter: QuerableStreamingMultiMapGetter<K,V>,
     Getter2: QuerableStreamingMultiMapGetter<K,V2>>
     QuerableStreamingMultiMapGetter<K,(V, V2)> 
@@ -351,7 +885,30 @@ impl <'source, K:Eq+Hash+Clone,V:Eq+Hash+Clone, V2:Eq+Hash+Clone,
                     r.insert((v.clone(), v2.clone()));
                 }
             }
-            return r;
+            r
+        }
+        fn items(&self)->Vec<(K, (V, V2))> {
+            let mut r=Vec::new();
+            for (k, v) in self.source.items() {
+                for v2 in self.source2.get(&k) {
+                    r.push((k.clone(), (v.clone(), v2)));
+                }
+            }
+            r
+        }
+        fn counts(&self, key: &K)->HashMap<(V, V2), u64> {
+            let counts2=self.source2.counts(key);
+            self.source.counts(key).into_iter().flat_map(|(v, count)| counts2.iter()
+                .map(move |(v2, count2)| ((v.clone(), v2.clone()), count*count2))).collect()
+        }
+        fn weighted_items(&self)->Vec<((K, (V, V2)), u64)> {
+            let mut r=Vec::new();
+            for ((k, v), count) in self.source.weighted_items() {
+                for (v2, count2) in self.source2.counts(&k) {
+                    r.push(((k.clone(), (v.clone(), v2)), count*count2));
+                }
+            }
+            r
         }
 }
 
@@ -372,7 +929,7 @@ impl <'source, 'listener, 'last_source, K:Eq+Hash+Clone,V:Eq+Hash+Clone, V2:Eq+H
     MessageListenersInterface<'listener, MultiSetModifyMessage<(K,(V, V2))>> 
     for JoinQuerableStreamingMultiMap<'source, 'listener, 'last_source, K,V, V2, Source, Source2> {
         fn listeners(&self)->&MessageListeners<'listener, MultiSetModifyMessage<(K,(V, V2))>> {
-            &*self.listeners
+            &self.state.listeners
         }
 }
 
@@ -383,83 +940,371 @@ impl<'source, 'listener, 'last_source, K:Eq+Hash+Clone,V:Eq+Hash+Clone, V2:Eq+Ha
     pub fn new(source: &'last_source Source, source2: &'last_source Source2)->Self {
         let source_getter=RcBorrow::new(source.getter());
         let source2_getter=RcBorrow::new(source2.getter());
-        let mut r = Self {
-            source: RcBorrow::new(source),
-            source2: RcBorrow::new(source2),
-            listeners: Rc::new(MultiSetMessageListeners::new()),
-            source_cancel_index: None,
-            source2_cancel_index: None,
-            
+        let listeners=Rc::new(MultiSetMessageListeners::new());
+        listeners.depends_on(source.listeners());
+        listeners.depends_on(source2.listeners());
+        let state=Rc::new(JoinState {
+            listeners,
+            source: source_getter.get(),
+            source2: source2_getter.get(),
+            pending: RefCell::new(JoinPending { source: Vec::new(), source2: Vec::new(), scheduled: false })
+        });
+
+        let weak=Rc::downgrade(&state);
+        let source_subscription=source.listeners().listen(move |message| {
+            if let Some(state)=weak.upgrade() {
+                state.push(|pending| pending.source.push(message));
+            }
+        });
+        let weak=Rc::downgrade(&state);
+        let source2_subscription=source2.listeners().listen(move |message| {
+            if let Some(state)=weak.upgrade() {
+                state.push(|pending| pending.source2.push(message));
+            }
+        });
+        Self {
+            _source_subscription: source_subscription,
+            _source2_subscription: source2_subscription,
+            state,
             getter: JoinQuerableStreamingMultiMapGetter {
                 source: source_getter.get(),
                 source2: source2_getter.get(),
                 phantom_data: PhantomData
             },
-            source_getter,
-            source2_getter};
-        let rlisteners=r.listeners.clone();
-        let csource=r.getter.source.clone();
-        let csource2=r.getter.source2.clone();
-
-        r.source_cancel_index=Some(source.listeners().listen(move |message| {
-            match message {
-                MultiSetModifyMessage::InsertOne((key, value))=> {
-                    for value2 in csource2.get(&key) {
-                        rlisteners.send(MultiSetModifyMessage::InsertOne((key.clone(), (value.clone(), value2.clone()))));
-                    }
-                },
-                MultiSetModifyMessage::RemoveOne((key, value))=>{
-                    for value2 in csource2.get(&key) {
-                        rlisteners.send(MultiSetModifyMessage::RemoveOne((key.clone(), (value.clone(), value2.clone()))));
-                    }
-                }
+            _source_getter: source_getter,
+            _source2_getter: source2_getter}
+    }
+}
+
+/// Combines the values of a key of the two inputs of a `KeyJoinQuerableStreamingMultiMap`.
+///
+/// Every copy of a value of the first input is combined with the values of the second input
+///   (with their multiplicities) on its own, so the join can send the changes of the first
+///   input combined with the second input, and the changes of the second input combined with
+///   each value of the first input, like `JoinQuerableStreamingMultiMap`.
+pub struct KeyJoinCombine<V, V2, Out> {
+    /// The values of the join for one copy of a value of the first input.
+    pub values: fn(&V, &HashMap<V2, u64>)->HashMap<Out, u64>,
+    /// The change of `values` for a value of the first input when the values of the second
+    ///   input change from the first map to the second one by the consolidated changes.
+    pub changes: KeyJoinValueChanges<V, V2, Out>
+}
+
+/// See `KeyJoinCombine::changes`.
+pub type KeyJoinValueChanges<V, V2, Out>=fn(&V, &HashMap<V2, u64>, &HashMap<V2, u64>, &[(V2, i64)])->Vec<(Out, i64)>;
+
+impl<V, V2, Out> Clone for KeyJoinCombine<V, V2, Out> {
+    fn clone(&self)->Self {
+        *self
+    }
+}
+
+impl<V, V2, Out> Copy for KeyJoinCombine<V, V2, Out> {}
+
+impl<V, V2, Out: Eq+Hash> KeyJoinCombine<V, V2, Out> {
+    /// The values of the join for the values of a key of the first and the second input.
+    fn combine(&self, values: &HashMap<V, u64>, values2: &HashMap<V2, u64>)->HashMap<Out, u64> {
+        let mut r=HashMap::new();
+        for (v, count) in values {
+            for (out, out_count) in (self.values)(v, values2) {
+                *r.entry(out).or_insert(0)+=count*out_count;
             }
-        }));
-        let rlisteners=r.listeners.clone();
+        }
+        r
+    }
+}
 
-        r.source2_cancel_index=Some(source2.listeners().listen(move |message| {
-            match message {
-                MultiSetModifyMessage::InsertOne((key, value2))=> {
-                    for value in csource.get(&key) {
-                        rlisteners.send(MultiSetModifyMessage::InsertOne((key.clone(), (value, value2.clone()))));
-                    }
-                },
-                MultiSetModifyMessage::RemoveOne((key, value2))=>{
-                    for value in csource.get(&key) {
-                        rlisteners.send(MultiSetModifyMessage::RemoveOne((key.clone(), (value, value2.clone()))));
-                    }
+/// The changes from the `old` to the `new` multiplicities of values.
+fn count_changes<V: Eq+Hash+Clone>(old: &HashMap<V, u64>, new: &HashMap<V, u64>)->Vec<(V, i64)> {
+    let count=|counts: &HashMap<V, u64>, v: &V| counts.get(v).copied().unwrap_or(0) as i64;
+    old.keys().chain(new.keys().filter(|v| !old.contains_key(v)))
+        .map(|v| (v.clone(), count(new, v)-count(old, v))).filter(|(_, diff)| *diff!=0).collect()
+}
+
+/// A join whose values for a key are combined from the values of the key of both inputs by
+///   a `KeyJoinCombine`, used for the joins that depend on whether a key has a match:
+///   `left_join`, `semi_join` and `anti_join`.
+///
+/// Like `JoinQuerableStreamingMultiMap`, the join isn't materialized: `get` reads through to
+///   the inputs, and the changes of an update are sent once, when both inputs are up to date.
+///   When a key loses exactly one value and gains exactly one, the change is sent as `Replace`.
+pub struct KeyJoinQuerableStreamingMultiMap<'source, 'listener, 'last_source, K:Eq+Hash+Clone+'static,V:Eq+Hash+Clone+'static,
+    V2:Eq+Hash+Clone+'static, Out:Eq+Hash+Clone+'static,
+    Source: QuerableStreamingMultiMap<'source, 'listener, K,V>,
+    Source2: QuerableStreamingMultiMap<'source, 'listener, K,V2>> {
+    _source_subscription: Subscription<'listener>,
+    _source2_subscription: Subscription<'listener>,
+    // The state holds clones of the getters, so it has to be dropped before the RcBorrows.
+    state: SharedKeyJoinState<'listener, K, V, V2, Out, Source::Getter, Source2::Getter>,
+    getter: KeyJoinQuerableStreamingMultiMapGetter<K,V,V2,Out, Source::Getter, Source2::Getter>,
+    _source_getter: RcBorrow<'last_source, Source::Getter>,
+    _source2_getter: RcBorrow<'last_source, Source2::Getter>,
+}
+
+/// The values of a `left_join`: `None` stands for a key without values in the second input.
+pub type LeftJoinQuerableStreamingMultiMap<'source, 'listener, 'last_source, K, V, V2, Source, Source2>=
+    KeyJoinQuerableStreamingMultiMap<'source, 'listener, 'last_source, K, V, V2, (V, Option<V2>), Source, Source2>;
+
+fn left_join_values<V: Eq+Hash+Clone, V2: Eq+Hash+Clone>(value: &V, values2: &HashMap<V2, u64>)->HashMap<(V, Option<V2>), u64> {
+    if values2.is_empty() {
+        return HashMap::from([((value.clone(), None), 1)]);
+    }
+    values2.iter().map(|(v2, count2)| ((value.clone(), Some(v2.clone())), *count2)).collect()
+}
+
+// Only a key that gains its first or loses its last value of the second input changes the
+//   `None` rows.
+fn left_join_changes<V: Eq+Hash+Clone, V2: Eq+Hash+Clone>(value: &V, old2: &HashMap<V2, u64>, new2: &HashMap<V2, u64>,
+        changes2: &[(V2, i64)])->Vec<((V, Option<V2>), i64)> {
+    if old2.is_empty()!=new2.is_empty() {
+        return count_changes(&left_join_values(value, old2), &left_join_values(value, new2));
+    }
+    changes2.iter().map(|(v2, diff)| ((value.clone(), Some(v2.clone())), *diff)).collect()
+}
+
+type SharedKeyJoinState<'listener, K, V, V2, Out, SourceGetter, SourceGetter2>=
+    Rc<KeyJoinState<'listener, K, V, V2, Out, SourceGetter, SourceGetter2>>;
+
+/// The consolidated changes of a key in the two inputs.
+type KeyJoinChanges<V, V2>=(Vec<(V, i64)>, Vec<(V2, i64)>);
+
+fn semi_join_values<V: Eq+Hash+Clone, V2>(value: &V, values2: &HashMap<V2, u64>)->HashMap<V, u64> {
+    if values2.is_empty() { HashMap::new() } else { HashMap::from([(value.clone(), 1)]) }
+}
+
+fn semi_join_changes<V: Eq+Hash+Clone, V2>(value: &V, old2: &HashMap<V2, u64>, new2: &HashMap<V2, u64>, _: &[(V2, i64)])->Vec<(V, i64)> {
+    match (old2.is_empty(), new2.is_empty()) {
+        (true, false)=>vec![(value.clone(), 1)],
+        (false, true)=>vec![(value.clone(), -1)],
+        _=>Vec::new()
+    }
+}
+
+fn anti_join_values<V: Eq+Hash+Clone, V2>(value: &V, values2: &HashMap<V2, u64>)->HashMap<V, u64> {
+    if values2.is_empty() { HashMap::from([(value.clone(), 1)]) } else { HashMap::new() }
+}
+
+fn anti_join_changes<V: Eq+Hash+Clone, V2>(value: &V, old2: &HashMap<V2, u64>, new2: &HashMap<V2, u64>, changes2: &[(V2, i64)])->Vec<(V, i64)> {
+    semi_join_changes(value, old2, new2, changes2).into_iter().map(|(v, diff)| (v, -diff)).collect()
+}
+
+struct KeyJoinState<'listener, K:Eq+Hash+Clone+'static, V:Eq+Hash+Clone+'static, V2:Eq+Hash+Clone+'static, Out:Eq+Hash+Clone+'static,
+        SourceGetter, SourceGetter2> {
+    listeners: Rc<MultiSetMessageListeners<'listener, (K, Out)>>,
+    source: Rc<Borrow<SourceGetter>>,
+    source2: Rc<Borrow<SourceGetter2>>,
+    combine: KeyJoinCombine<V, V2, Out>,
+    pending: RefCell<JoinPending<K, V, V2>>
+}
+
+/// The multiplicities of the values of a key before the consolidated changes were applied.
+pub(crate) fn old_counts<V: Eq+Hash+Clone>(mut counts: HashMap<V, u64>, changes: &[(V, i64)])->HashMap<V, u64> {
+    for (value, diff) in changes {
+        let count=counts.get(value).copied().unwrap_or(0) as i64-diff;
+        if count>0 {
+            counts.insert(value.clone(), count as u64);
+        } else {
+            counts.remove(value);
+        }
+    }
+    counts
+}
+
+impl<'listener, K:Eq+Hash+Clone+'static, V:Eq+Hash+Clone+'static, V2:Eq+Hash+Clone+'static, Out:Eq+Hash+Clone+'static,
+    SourceGetter: QuerableStreamingMultiMapGetter<K,V>+'listener,
+    SourceGetter2: QuerableStreamingMultiMapGetter<K,V2>+'listener>
+    KeyJoinState<'listener, K, V, V2, Out, SourceGetter, SourceGetter2> {
+    fn push(self: &Rc<Self>, f: impl FnOnce(&mut JoinPending<K, V, V2>)) {
+        let schedule={
+            let mut pending=self.pending.borrow_mut();
+            f(&mut pending);
+            !std::mem::replace(&mut pending.scheduled, true)
+        };
+        if schedule {
+            let weak=Rc::downgrade(self);
+            self.listeners.schedule(move || if let Some(state)=weak.upgrade() { state.flush() });
+        }
+    }
+
+    /// Sends the changes of the join for the buffered input changes of every key:
+    ///   changes of the first input combined with the old values of the second input, plus
+    ///   the changes of the second input combined with the new values of the first input.
+    fn flush(&self) {
+        let (source, source2)={
+            let mut pending=self.pending.borrow_mut();
+            pending.scheduled=false;
+            (std::mem::take(&mut pending.source), std::mem::take(&mut pending.source2))
+        };
+        // Clearing an input clears the join, except for an anti join which sends inserts then.
+        let cleared=source.iter().any(|message| matches!(message.first(), Some(MultiSetModifyMessage::Clear(_)))) ||
+            source2.iter().any(|message| matches!(message.first(), Some(MultiSetModifyMessage::Clear(_))));
+        let mut keys=Vec::new();
+        let mut changes: HashMap<K, KeyJoinChanges<V, V2>>=HashMap::new();
+        for ((key, value), diff) in MultiSetModifyMessage::consolidate(source) {
+            changes.entry(key.clone()).or_insert_with(|| {
+                keys.push(key);
+                Default::default()
+            }).0.push((value, diff));
+        }
+        for ((key, value2), diff) in MultiSetModifyMessage::consolidate(source2) {
+            changes.entry(key.clone()).or_insert_with(|| {
+                keys.push(key);
+                Default::default()
+            }).1.push((value2, diff));
+        }
+        let mut messages=Vec::new();
+        for key in keys {
+            let (key_changes, key_changes2)=&changes[&key];
+            let values2=self.source2.counts(&key);
+            let old2=old_counts(values2.clone(), key_changes2);
+            let mut joined=Vec::new();
+            for (value, diff) in key_changes {
+                joined.extend((self.combine.values)(value, &old2).into_iter().map(|(out, count)| (out, diff*count as i64)));
+            }
+            if !key_changes2.is_empty() {
+                for (value, count) in self.source.counts(&key) {
+                    joined.extend((self.combine.changes)(&value, &old2, &values2, key_changes2).into_iter()
+                        .map(|(out, diff)| (out, count as i64*diff)));
                 }
             }
-        }));
-        r
+            push_value_changes(&mut messages, key, joined);
+        }
+        send_key_changes(&self.listeners, messages, cleared);
     }
 }
 
-impl<'source, 'listener, 'last_source, K:Eq+Hash+Clone,V:Eq+Hash+Clone, V2:Eq+Hash+Clone, 
+/// Adds the changes of the values of a key (consolidating them first), as a `Replace` if
+///   exactly one copy of a value was swapped for a copy of another.
+pub(crate) fn push_value_changes<K: Eq+Hash+Clone, V: Eq+Hash+Clone>(messages: &mut Vec<MultiSetModifyMessage<(K, V)>>, key: K,
+        changes: Vec<(V, i64)>) {
+    let changes=MultiSetModifyMessage::consolidate(MultiSetModifyMessage::from_consolidated(changes));
+    if let [(old, -1), (new, 1)] | [(new, 1), (old, -1)]=&changes[..] {
+        messages.push(MultiSetModifyMessage::Replace { old: (key.clone(), old.clone()), new: (key, new.clone()) });
+        return;
+    }
+    messages.extend(changes.into_iter().map(|(v, diff)| MultiSetModifyMessage::weighted((key.clone(), v), diff)));
+}
+
+/// Sends the changes of a join as one message; removing everything after an input was
+///   cleared is sent as `Clear`.
+pub(crate) fn send_key_changes<K: Eq+Hash+Clone+'static, V: Eq+Hash+Clone+'static>(listeners: &MultiSetMessageListeners<'_, (K, V)>,
+        messages: Vec<MultiSetModifyMessage<(K, V)>>, cleared: bool) {
+    let messages=if cleared {
+        MultiSetModifyMessage::group_removes(messages, MultiSetModifyMessage::Clear)
+    } else {
+        messages
+    };
+    if let Some(message)=MultiSetModifyMessage::from_messages(messages) {
+        listeners.send(message);
+    }
+}
+
+pub struct KeyJoinQuerableStreamingMultiMapGetter<K:Eq+Hash+Clone+'static, V:Eq+Hash+Clone+'static, V2:Eq+Hash+Clone+'static,
+    Out:Eq+Hash+Clone+'static,
+    SourceGetter: QuerableStreamingMultiMapGetter<K,V>,
+    SourceGetter2: QuerableStreamingMultiMapGetter<K,V2>> {
+    source: Rc<Borrow<SourceGetter>>,
+    source2: Rc<Borrow<SourceGetter2>>,
+    combine: KeyJoinCombine<V, V2, Out>,
+    phantom_data: PhantomData<K>
+}
+
+impl <K:Eq+Hash+Clone,V:Eq+Hash+Clone, V2:Eq+Hash+Clone, Out:Eq+Hash+Clone,
+    Getter: QuerableStreamingMultiMapGetter<K,V>,
+    Getter2: QuerableStreamingMultiMapGetter<K,V2>>
+    QuerableStreamingMultiMapGetter<K,Out>
+    for KeyJoinQuerableStreamingMultiMapGetter<K,V, V2, Out, Getter, Getter2> {
+        fn get(&self, key: &K)->HashSet<Out> {
+            self.counts(key).into_keys().collect()
+        }
+        fn items(&self)->Vec<(K, Out)> {
+            self.weighted_items().into_iter().map(|(pair, _)| pair).collect()
+        }
+        fn counts(&self, key: &K)->HashMap<Out, u64> {
+            self.combine.combine(&self.source.counts(key), &self.source2.counts(key))
+        }
+        // Every value of the join has a value of the first input, so only its keys are visited.
+        fn weighted_items(&self)->Vec<((K, Out), u64)> {
+            let mut keys=HashSet::new();
+            let mut r=Vec::new();
+            for (k, _) in self.source.items() {
+                if keys.insert(k.clone()) {
+                    r.extend(self.counts(&k).into_iter().map(|(out, count)| ((k.clone(), out), count)));
+                }
+            }
+            r
+        }
+}
+
+impl<'source, 'listener, 'last_source, K:Eq+Hash+Clone,V:Eq+Hash+Clone, V2:Eq+Hash+Clone, Out:Eq+Hash+Clone,
     Source: QuerableStreamingMultiMap<'source, 'listener, K,V>,
     Source2: QuerableStreamingMultiMap<'source, 'listener, K,V2>>
-    Drop for JoinQuerableStreamingMultiMap<'source, 'listener, 'last_source, K,V, V2, Source, Source2> {
-    fn drop(&mut self) {
-        if let Some(index)=self.source_cancel_index {
-            self.source.get().listeners().cancel(index);
+    QuerableStreamingMultiMap<'source, 'listener, K,Out>
+    for KeyJoinQuerableStreamingMultiMap<'source, 'listener, 'last_source, K,V, V2, Out, Source, Source2> {
+        type Getter = KeyJoinQuerableStreamingMultiMapGetter<K,V, V2, Out, Source::Getter, Source2::Getter>;
+        fn getter(&self)->&Self::Getter {
+            &self.getter
         }
-        if let Some(index)=self.source2_cancel_index {
-            self.source2.get().listeners().cancel(index);
+}
+
+impl <'source, 'listener, 'last_source, K:Eq+Hash+Clone,V:Eq+Hash+Clone, V2:Eq+Hash+Clone, Out:Eq+Hash+Clone,
+    Source: QuerableStreamingMultiMap<'source, 'listener, K,V>,
+    Source2: QuerableStreamingMultiMap<'source, 'listener, K,V2>>
+    MessageListenersInterface<'listener, MultiSetModifyMessage<(K,Out)>>
+    for KeyJoinQuerableStreamingMultiMap<'source, 'listener, 'last_source, K,V, V2, Out, Source, Source2> {
+        fn listeners(&self)->&MessageListeners<'listener, MultiSetModifyMessage<(K,Out)>> {
+            &self.state.listeners
         }
+}
+
+impl<'source, 'listener, 'last_source, K:Eq+Hash+Clone,V:Eq+Hash+Clone, V2:Eq+Hash+Clone, Out:Eq+Hash+Clone,
+    Source: QuerableStreamingMultiMap<'source, 'listener, K,V>,
+    Source2: QuerableStreamingMultiMap<'source, 'listener, K,V2>>
+    KeyJoinQuerableStreamingMultiMap<'source, 'listener, 'last_source, K,V, V2, Out, Source, Source2> {
+    pub fn new(source: &'last_source Source, source2: &'last_source Source2, combine: KeyJoinCombine<V, V2, Out>)->Self {
+        let source_getter=RcBorrow::new(source.getter());
+        let source2_getter=RcBorrow::new(source2.getter());
+        let listeners=Rc::new(MultiSetMessageListeners::new());
+        listeners.depends_on(source.listeners());
+        listeners.depends_on(source2.listeners());
+        let state=Rc::new(KeyJoinState {
+            listeners,
+            source: source_getter.get(),
+            source2: source2_getter.get(),
+            combine,
+            pending: RefCell::new(JoinPending { source: Vec::new(), source2: Vec::new(), scheduled: false })
+        });
+
+        let weak=Rc::downgrade(&state);
+        let source_subscription=source.listeners().listen(move |message| {
+            if let Some(state)=weak.upgrade() {
+                state.push(|pending| pending.source.push(message));
+            }
+        });
+        let weak=Rc::downgrade(&state);
+        let source2_subscription=source2.listeners().listen(move |message| {
+            if let Some(state)=weak.upgrade() {
+                state.push(|pending| pending.source2.push(message));
+            }
+        });
+        Self {
+            _source_subscription: source_subscription,
+            _source2_subscription: source2_subscription,
+            state,
+            getter: KeyJoinQuerableStreamingMultiMapGetter {
+                source: source_getter.get(),
+                source2: source2_getter.get(),
+                combine,
+                phantom_data: PhantomData
+            },
+            _source_getter: source_getter,
+            _source2_getter: source2_getter}
     }
 }
 
 impl<'a, K:Eq+Hash+Clone+'static,V:Eq+Hash+Clone+'static> MultiSetMessageListeners<'a, (K,V)> {
-    pub fn group(&'a self)->Rc<StreamingHashMultiMapWithCount<K,V>> {
-        let r=Rc::new(StreamingHashMultiMapWithCount::new());
-        let rclone=r.clone();
-        self.listeners().listen(move |message| {
-                match message {
-                    MultiSetModifyMessage::InsertOne((k,v))=>{rclone.insert(k.clone(),v.clone());},
-                    MultiSetModifyMessage::RemoveOne((k,v))=>{rclone.remove(k.clone(),v.clone());},
-                };
-            });
-        r
+    pub fn group(&'a self)->Rc<StreamingHashMultiMapWithCount<'a, K,V>> {
+        self.group_by(|k, v| (k, v))
     }
 
     pub fn reversed<'last_source>(&'last_source self)->
@@ -470,20 +1315,14 @@ impl<'a, K:Eq+Hash+Clone+'static,V:Eq+Hash+Clone+'static> MultiSetMessageListene
     pub fn group_by<'last_source, K2: Eq+Hash+Clone+'static, V2: Eq+Hash+Clone+'static>(
             &'last_source self, f:impl Fn(K, V)->(K2, V2) + 'a)->
             Rc<StreamingHashMultiMapWithCount<'a, K2,V2>> {
-        let r=Rc::new(StreamingHashMultiMapWithCount::new());
-        let rclone=r.clone();
-        self.listeners().listen(move |message| {
-                match message {
-                    MultiSetModifyMessage::InsertOne((k,v))=>{
-                        let (k2, v2)=f(k.clone(), v.clone());
-                        rclone.insert(k2, v2);
-                    },
-                    MultiSetModifyMessage::RemoveOne((k,v))=>{
-                        let (k2, v2)=f(k.clone(), v.clone());
-                        rclone.remove(k2, v2);
-                    },
-                };
-            });
+        let r: Rc<StreamingHashMultiMapWithCount<'a, K2, V2>>=Rc::new(StreamingHashMultiMapWithCount::new());
+        r.listeners.depends_on(self);
+        let weak=Rc::downgrade(&r);
+        r.listeners.hold(self.listeners().listen(move |message| {
+                if let Some(r)=weak.upgrade() {
+                    r.apply(message.map_items(&|(k, v)| f(k, v)));
+                }
+            }));
         r
     }
 }
@@ -514,8 +1353,8 @@ fn test_get_one_none() {
 
 #[test]
 fn test_join() {
-    let mut map1 = StreamingHashMultiMapWithCount::new();
-    let mut map2 = StreamingHashMultiMapWithCount::new();
+    let map1 = StreamingHashMultiMapWithCount::new();
+    let map2 = StreamingHashMultiMapWithCount::new();
     let joined_map = map1.join(&map2);
     map1.insert("key", "value");
     map2.insert("key", "value2");
@@ -545,8 +1384,8 @@ fn test_filter2() {
 
 #[test]
 fn test_filter_join() {
-    let mut map1 = StreamingHashMultiMapWithCount::new();
-    let mut map2 = StreamingHashMultiMapWithCount::new();
+    let map1 = StreamingHashMultiMapWithCount::new();
+    let map2 = StreamingHashMultiMapWithCount::new();
     let filter_map = map1.filter_item(|k, _| k=="key");
     let joined_map = filter_map.join(&map2);
     map1.insert("key", "value");
@@ -559,7 +1398,7 @@ fn test_filter_join() {
 
 #[test]
 fn test_group_by() {
-    let mut map1 = StreamingHashMultiMapWithCount::new();
+    let map1 = StreamingHashMultiMapWithCount::new();
     let group_map = map1.group_by(|k, v| (v, k));
     map1.insert("key", "value");
     map1.insert("key", "value2");
@@ -567,14 +1406,475 @@ fn test_group_by() {
     assert_eq!(group_map.get_one(&"value"), Some("key"));
 }
 
+#[test]
+fn test_trigger_inserting_into_observed_map() {
+    let map = Rc::new(StreamingHashMultiMapWithCount::new());
+    let weak_map = Rc::downgrade(&map);
+    map.listen(move |message| {
+        if let MultiSetModifyMessage::InsertOne((k, v)) = message {
+            if v < 3 {
+                weak_map.upgrade().unwrap().insert(k, v + 1);
+            }
+        }
+    }).detach();
+    map.insert("key", 1);
+    assert_eq!(map.get(&"key"), HashSet::from_iter(vec![1, 2, 3]));
+}
+
+#[test]
+fn test_dropping_operators_disconnects_them() {
+    let map1 = StreamingHashMultiMapWithCount::new();
+    let map2 = StreamingHashMultiMapWithCount::new();
+    let grouped = map1.group_by(|k, v| (v, k));
+    let weak_grouped = Rc::downgrade(&grouped);
+    drop(grouped);
+    assert!(weak_grouped.upgrade().is_none());
+    let (filtered_grouped, joined_grouped) = {
+        let filter_map = map1.filter_item(|_, _| true);
+        let joined_map = map1.join(&map2);
+        (filter_map.group_by(|k, v| (k, v)), joined_map.group_by(|k, v| (k, v)))
+    };
+    map1.insert("key", "value");
+    map2.insert("key", "value2");
+    assert_eq!(filtered_grouped.get_one(&"key"), None);
+    assert_eq!(joined_grouped.get_one(&"key"), None);
+}
+
+#[test]
+fn test_transaction_sends_one_batch() {
+    let map = StreamingHashMultiMapWithCount::new();
+    let reversed = map.reversed();
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let mclone = messages.clone();
+    reversed.listen(move |message| mclone.borrow_mut().push(message)).detach();
+    map.insert("client", "uid1");
+    messages.borrow_mut().clear();
+    map.transaction(|tx| {
+        tx.set("client", "uid2");
+        tx.insert("client2", "uid3");
+        tx.remove("client2", "uid3");
+        tx.insert("client3", "uid1");
+    });
+    assert_eq!(*messages.borrow(), vec![MultiSetModifyMessage::Batch(vec![
+        MultiSetModifyMessage::Replace { old: ("uid1", "client"), new: ("uid2", "client") },
+        MultiSetModifyMessage::InsertOne(("uid1", "client3"))])]);
+    assert_eq!(reversed.get(&"uid1"), HashSet::from_iter(vec!["client3"]));
+    messages.borrow_mut().clear();
+    map.set("client", "uid2");
+    assert!(messages.borrow().is_empty());
+    // Without an old value to replace, the new value is only inserted.
+    map.transaction(|tx| tx.replace("client", "uid1", "uid3"));
+    assert_eq!(*messages.borrow(), vec![MultiSetModifyMessage::InsertOne(("uid3", "client"))]);
+    assert_eq!(map.get(&"client"), HashSet::from_iter(vec!["uid2", "uid3"]));
+}
+
+#[test]
+fn test_transaction_through_join() {
+    let map1 = StreamingHashMultiMapWithCount::new();
+    let map2 = StreamingHashMultiMapWithCount::new();
+    let joined_map = map1.join(&map2);
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let mclone = messages.clone();
+    let _subscription = joined_map.listen(move |message| mclone.borrow_mut().push(message));
+    map2.insert("key", "value2");
+    map1.transaction(|tx| {
+        tx.insert("key", "value");
+        tx.insert("key", "value3");
+    });
+    assert_eq!(messages.borrow().len(), 1);
+    assert_eq!(messages.borrow()[0].clone().into_messages().len(), 2);
+}
+
+#[test]
+fn test_join_with_itself_sends_once() {
+    let map = StreamingHashMultiMapWithCount::new();
+    let joined_map = map.join(&map);
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let mclone = messages.clone();
+    let _subscription = joined_map.listen(move |message| mclone.borrow_mut().push(message));
+    map.insert("key", "value");
+    map.remove("key", "value");
+    assert_eq!(*messages.borrow(), vec![
+        MultiSetModifyMessage::InsertOne(("key", ("value", "value"))),
+        MultiSetModifyMessage::RemoveOne(("key", ("value", "value")))]);
+}
+
+#[test]
+fn test_diamond_join_sees_settled_inputs() {
+    // The group_by listens to map before the filter, so without topological ordering the join
+    // would see the group_by change while the filter's getter already shows the new value,
+    // and then get the filter change too, sending the joined pair twice.
+    let map = StreamingHashMultiMapWithCount::new();
+    let grouped = map.group_by(|k, v| (k, v));
+    let filter_map = map.filter_item(|_, _| true);
+    let joined_map = grouped.join(&filter_map);
+    let counts = joined_map.group_by(|k, v| (k, v));
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let mclone = messages.clone();
+    let _subscription = joined_map.listen(move |message| mclone.borrow_mut().push(message));
+    map.insert("key", "value");
+    assert_eq!(*messages.borrow(), vec![MultiSetModifyMessage::InsertOne(("key", ("value", "value")))]);
+    map.remove("key", "value");
+    assert_eq!(counts.get(&"key"), HashSet::new());
+    assert_eq!(grouped.listeners().rank(), 1);
+    assert_eq!(joined_map.listeners().rank(), 2);
+}
+
 #[test]
 fn test_reversed() {
-    let mut map1 = StreamingHashMultiMapWithCount::new();
+    let map1 = StreamingHashMultiMapWithCount::new();
     map1.insert("key", "value");
     map1.insert("key", "value2");
     map1.insert("key2", "value3");
-    // let group_map = map1.reversed();
-    let l=map1.listeners();
-    // let group_map = l.reversed();
-    // assert_eq!(group_map.get_one(&"value"), Some("key"));
-}
\ No newline at end of file
+    let group_map = map1.reversed();
+    assert_eq!(group_map.get_one(&"value"), Some("key"));
+    assert_eq!(group_map.get_one(&"value3"), Some("key2"));
+    map1.remove("key", "value");
+    assert_eq!(group_map.get_one(&"value"), None);
+}
+
+#[test]
+fn test_late_operators_backfill() {
+    let map1 = StreamingHashMultiMapWithCount::new();
+    let map2 = StreamingHashMultiMapWithCount::new();
+    map1.insert("key", "value");
+    map1.insert("key2", "value3");
+    map2.insert("key", "value2");
+    let filter_map = map1.filter_item(|k, _| k=="key");
+    let joined_map = filter_map.join(&map2);
+    assert_eq!(joined_map.items(), vec![("key", ("value", "value2"))]);
+    let grouped = joined_map.group_by(|k, (v, v2)| (v2, (k, v)));
+    assert_eq!(grouped.get_one(&"value2"), Some(("key", "value")));
+    let future_only = map1.listeners().group_by(|k, v| (k, v));
+    assert_eq!(future_only.items(), vec![]);
+    map1.insert("key", "value4");
+    assert_eq!(future_only.items(), vec![("key", "value4")]);
+    assert_eq!(grouped.get(&"value2"), HashSet::from_iter(vec![("key", "value"), ("key", "value4")]));
+}
+
+#[test]
+fn test_weighted_messages() {
+    let map = StreamingHashMultiMapWithCount::new();
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let mclone = messages.clone();
+    let _subscription = map.listen(move |message| mclone.borrow_mut().push(message));
+    map.apply(MultiSetModifyMessage::Delta(("key", "value"), 1000));
+    map.insert("key", "value");
+    map.add("key", "value", -1001);
+    assert_eq!(map.count(&"key", &"value"), 0);
+    assert_eq!(*messages.borrow(), vec![MultiSetModifyMessage::Delta(("key", "value"), 1000),
+        MultiSetModifyMessage::InsertOne(("key", "value")), MultiSetModifyMessage::Delta(("key", "value"), -1001)]);
+}
+
+#[test]
+fn test_weights_through_operators() {
+    let map = StreamingHashMultiMapWithCount::new();
+    let map2 = StreamingHashMultiMapWithCount::new();
+    map2.insert("key", "value2");
+    map2.insert("key", "value2");
+    let counts = map.group_by(|k, v| (v, k));
+    let filtered = map.filter_item(|k, _| k=="key");
+    let filtered_counts = filtered.group_by(|k, v| (k, v));
+    map.add("key", "value", 5);
+    map.add("key2", "value", 3);
+    assert_eq!(counts.count(&"value", &"key"), 5);
+    assert_eq!(counts.count(&"value", &"key2"), 3);
+    assert_eq!(filtered_counts.count(&"key", &"value"), 5);
+    assert_eq!(filtered_counts.count(&"key2", &"value"), 0);
+    // The join multiplies the weights of the joined pairs.
+    let reversed2 = map2.reversed();
+    let joined_map = counts.join(&*reversed2);
+    let joined_counts = joined_map.group_by(|k, v| (k, v));
+    let joined = Rc::new(RefCell::new(Vec::new()));
+    let jclone = joined.clone();
+    let _subscription = joined_map.listen(move |message| jclone.borrow_mut().push(message));
+    counts.add("value2", "key", 4);
+    map2.remove("key", "value2");
+    assert_eq!(*joined.borrow(), vec![MultiSetModifyMessage::Delta(("value2", ("key", "key")), 8),
+        MultiSetModifyMessage::Delta(("value2", ("key", "key")), -4)]);
+    assert_eq!(joined_counts.count(&"value2", &("key", "key")), 4);
+    let mut items = joined_map.weighted_items();
+    items.sort();
+    assert_eq!(items, vec![(("value2", ("key", "key")), 4)]);
+}
+
+#[test]
+fn test_set_sends_replace_through_operators() {
+    let map = StreamingHashMultiMapWithCount::new();
+    let map2 = StreamingHashMultiMapWithCount::new();
+    map.insert("tweet", "text");
+    map2.insert("tweet", "author");
+    let filter_map = map.filter_item(|_, v| v!="hidden");
+    let joined_map = filter_map.join(&map2);
+    let grouped = joined_map.group_by(|k, (text, author)| (author, (k, text)));
+    let filtered = Rc::new(RefCell::new(Vec::new()));
+    let grouped_messages = Rc::new(RefCell::new(Vec::new()));
+    let (fclone, gclone) = (filtered.clone(), grouped_messages.clone());
+    let _filter_subscription = filter_map.listen(move |message| fclone.borrow_mut().push(message));
+    let _subscription = grouped.listen(move |message| gclone.borrow_mut().push(message));
+    map.set("tweet", "edited");
+    assert_eq!(*filtered.borrow(), vec![MultiSetModifyMessage::Replace { old: ("tweet", "text"), new: ("tweet", "edited") }]);
+    assert_eq!(*grouped_messages.borrow(), vec![
+        MultiSetModifyMessage::Replace { old: ("author", ("tweet", "text")), new: ("author", ("tweet", "edited")) }]);
+    map.set("tweet", "hidden");
+    assert_eq!(filtered.borrow()[1], MultiSetModifyMessage::RemoveOne(("tweet", "edited")));
+    assert_eq!(grouped_messages.borrow()[1], MultiSetModifyMessage::RemoveOne(("author", ("tweet", "edited"))));
+    assert_eq!(grouped.get(&"author"), HashSet::new());
+}
+
+#[test]
+fn test_clear_remove_key_and_retain() {
+    let map = StreamingHashMultiMapWithCount::new();
+    let map2 = StreamingHashMultiMapWithCount::new();
+    map.insert("client", "uid1");
+    map.insert("client", "uid2");
+    map.insert("client2", "uid3");
+    map2.insert("client", "session");
+    map2.insert("client2", "session2");
+    let filter_map = map.filter_item(|_, v| v!="uid2");
+    let joined_map = filter_map.join(&map2);
+    let grouped = joined_map.group_by(|k, v| (k, v));
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let mclone = messages.clone();
+    let _subscription = joined_map.listen(move |message| mclone.borrow_mut().push(message));
+    assert!(map.remove_key(&"client"));
+    assert!(!map.remove_key(&"client"));
+    assert_eq!(*messages.borrow(), vec![MultiSetModifyMessage::ClearKey(vec![("client", ("uid1", "session"))])]);
+    map.insert("client", "uid1");
+    map.retain(|k, _| *k=="client");
+    assert_eq!(map.items(), vec![("client", "uid1")]);
+    messages.borrow_mut().clear();
+    map2.clear();
+    assert_eq!(*messages.borrow(), vec![MultiSetModifyMessage::Clear(vec![("client", ("uid1", "session"))])]);
+    assert_eq!(grouped.items(), vec![]);
+    map2.insert("client", "session");
+    map.transaction(|tx| {
+        tx.clear();
+        tx.insert("client", "uid1");
+        tx.insert("client3", "uid4");
+    });
+    assert_eq!(map.get(&"client3"), HashSet::from_iter(vec!["uid4"]));
+    assert_eq!(grouped.items(), vec![("client", ("uid1", "session"))]);
+    // A clear of some of the pairs only removes those, in a transaction as well.
+    map.apply(MultiSetModifyMessage::Clear(vec![("client3", "uid4")]));
+    assert_eq!(map.items(), vec![("client", "uid1")]);
+    map.insert("client3", "uid4");
+    map.transaction(|tx| tx.apply(MultiSetModifyMessage::Clear(vec![("client", "uid1")])));
+    assert_eq!(map.items(), vec![("client3", "uid4")]);
+    let copy = map.listeners().group_by(|k, v| (k, v));
+    let copy_messages = Rc::new(RefCell::new(Vec::new()));
+    let cclone = copy_messages.clone();
+    let _copy_subscription = copy.listen(move |message| cclone.borrow_mut().push(message));
+    copy.insert("client3", "uid4");
+    map.clear();
+    assert_eq!(copy_messages.borrow()[1], MultiSetModifyMessage::Clear(vec![("client3", "uid4")]));
+    // Emptying the map in a transaction is sent as a clear too.
+    map.insert("client", "uid1");
+    map.transaction(|tx| tx.clear());
+    assert_eq!(copy_messages.borrow()[3], MultiSetModifyMessage::Clear(vec![("client", "uid1")]));
+}
+
+#[test]
+fn test_expire_until() {
+    let start = NaiveDateTime::from_timestamp_opt(0, 0).unwrap();
+    let clock = Clock::manual(start);
+    let uid_by_client = StreamingHashMultiMapWithCount::with_clock(&clock);
+    let clients_by_uid = uid_by_client.reversed();
+    uid_by_client.set_ttl(Some(Duration::minutes(10)));
+    uid_by_client.insert("client1", "uid1");
+    uid_by_client.insert_with_ttl("client2", "uid2", Duration::hours(1));
+    uid_by_client.set_ttl(None);
+    uid_by_client.insert("client3", "uid3");
+    uid_by_client.insert("client4", "uid4");
+    uid_by_client.insert_with_ttl("client4", "uid4", Duration::minutes(1));
+    uid_by_client.remove("client4", "uid4");
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let mclone = messages.clone();
+    let _subscription = uid_by_client.listen(move |message| mclone.borrow_mut().push(message));
+    clock.set(start+Duration::seconds(30));
+    assert!(messages.borrow().is_empty());
+    clock.set(start+Duration::minutes(30));
+    assert_eq!(*messages.borrow(), vec![MultiSetModifyMessage::Batch(vec![MultiSetModifyMessage::RemoveOne(("client4", "uid4")),
+        MultiSetModifyMessage::RemoveOne(("client1", "uid1"))])]);
+    assert_eq!(clients_by_uid.get_one(&"uid1"), None);
+    // Writing a pair again restarts its time to live.
+    uid_by_client.insert_with_ttl("client2", "uid2", Duration::hours(2));
+    assert_eq!(uid_by_client.expire_until(start+Duration::minutes(90)), 0);
+    clock.set(start+Duration::days(1));
+    assert_eq!(uid_by_client.items(), vec![("client3", "uid3")]);
+}
+
+#[test]
+fn test_ttl_restarts_only_when_written() {
+    let start = NaiveDateTime::from_timestamp_opt(0, 0).unwrap();
+    let clock = Clock::manual(start);
+    let sessions = StreamingHashMultiMapWithCount::with_clock(&clock);
+    sessions.set_ttl(Some(Duration::minutes(10)));
+    sessions.add("client", "uid", 2);
+    clock.advance(Duration::minutes(5));
+    // Removing a copy doesn't restart the time to live, adding one does.
+    sessions.add("client", "uid", -1);
+    clock.advance(Duration::minutes(5));
+    assert_eq!(sessions.items(), vec![]);
+    sessions.insert("client", "uid");
+    clock.advance(Duration::minutes(5));
+    sessions.add("client", "uid", 1);
+    clock.advance(Duration::minutes(5));
+    assert_eq!(sessions.count(&"client", &"uid"), 2);
+    clock.advance(Duration::minutes(5));
+    assert_eq!(sessions.items(), vec![]);
+}
+
+#[test]
+fn test_ttl_is_forgotten_when_written_without_one() {
+    let start = NaiveDateTime::from_timestamp_opt(0, 0).unwrap();
+    let clock = Clock::manual(start);
+    let sessions = StreamingHashMultiMapWithCount::with_clock(&clock);
+    sessions.insert_with_ttl("client1", "uid1", Duration::minutes(10));
+    sessions.set_ttl(Some(Duration::minutes(10)));
+    sessions.insert("client2", "uid2");
+    sessions.set_ttl(None);
+    // Writing the pairs again without a time to live keeps them until they are removed.
+    sessions.insert("client1", "uid1");
+    sessions.add("client2", "uid2", 1);
+    clock.advance(Duration::hours(1));
+    assert_eq!(sessions.count(&"client1", &"uid1"), 2);
+    assert_eq!(sessions.count(&"client2", &"uid2"), 2);
+    // Restarting the time to live doesn't keep the old deadlines around.
+    sessions.set_ttl(Some(Duration::minutes(10)));
+    for _ in 0..100 {
+        sessions.insert("client1", "uid1");
+        clock.advance(Duration::minutes(1));
+    }
+    assert_eq!(sessions.expiry().borrow().queue.len(), 1);
+    clock.advance(Duration::minutes(10));
+    assert_eq!(sessions.items(), vec![("client2", "uid2")]);
+}
+
+#[test]
+fn test_left_join() {
+    let users = StreamingHashMultiMapWithCount::new();
+    let tweets = StreamingHashMultiMapWithCount::new();
+    users.insert("alice", "Alice");
+    users.insert("bob", "Bob");
+    tweets.insert("alice", "hello");
+    let joined_map = users.left_join(&tweets);
+    let grouped = joined_map.group_by(|k, v| (k, v));
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let mclone = messages.clone();
+    let _subscription = joined_map.listen(move |message| mclone.borrow_mut().push(message));
+    assert_eq!(joined_map.get_one(&"bob"), Some(("Bob", None)));
+    tweets.insert("bob", "first");
+    tweets.insert("bob", "second");
+    tweets.remove("bob", "first");
+    tweets.remove("bob", "second");
+    assert_eq!(*messages.borrow(), vec![
+        MultiSetModifyMessage::Replace { old: ("bob", ("Bob", None)), new: ("bob", ("Bob", Some("first"))) },
+        MultiSetModifyMessage::InsertOne(("bob", ("Bob", Some("second")))),
+        MultiSetModifyMessage::RemoveOne(("bob", ("Bob", Some("first")))),
+        MultiSetModifyMessage::Replace { old: ("bob", ("Bob", Some("second"))), new: ("bob", ("Bob", None)) }]);
+    messages.borrow_mut().clear();
+    // A key of the second input alone isn't part of the join.
+    tweets.insert("carol", "hi");
+    users.remove("alice", "Alice");
+    assert_eq!(*messages.borrow(), vec![MultiSetModifyMessage::RemoveOne(("alice", ("Alice", Some("hello"))))]);
+    assert_eq!(joined_map.items(), vec![("bob", ("Bob", None))]);
+    assert_eq!(grouped.items(), vec![("bob", ("Bob", None))]);
+}
+
+#[test]
+fn test_left_join_multiplies_weights() {
+    let users = StreamingHashMultiMapWithCount::new();
+    let tweets = StreamingHashMultiMapWithCount::new();
+    users.add("bob", "Bob", 2);
+    let joined_map = users.left_join(&tweets);
+    let grouped = joined_map.group_by(|k, v| (k, v));
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let mclone = messages.clone();
+    let _subscription = joined_map.listen(move |message| mclone.borrow_mut().push(message));
+    tweets.add("bob", "first", 3);
+    // Only the changed value of the second input is joined while the key has a match.
+    tweets.insert("bob", "second");
+    users.insert("bob", "Bob");
+    tweets.add("bob", "first", -3);
+    let mut weighted: Vec<_> = messages.borrow().iter().map(|message| {
+        let mut changes = MultiSetModifyMessage::consolidate([message.clone()]);
+        changes.sort();
+        changes
+    }).collect();
+    assert_eq!(weighted.remove(0), vec![(("bob", ("Bob", None)), -2), (("bob", ("Bob", Some("first"))), 6)]);
+    assert_eq!(weighted.remove(0), vec![(("bob", ("Bob", Some("second"))), 2)]);
+    assert_eq!(weighted.remove(0), vec![(("bob", ("Bob", Some("first"))), 3), (("bob", ("Bob", Some("second"))), 1)]);
+    assert_eq!(weighted.remove(0), vec![(("bob", ("Bob", Some("first"))), -9)]);
+    assert_eq!(grouped.weighted_items(), vec![(("bob", ("Bob", Some("second"))), 3)]);
+    tweets.remove("bob", "second");
+    assert_eq!(grouped.weighted_items(), vec![(("bob", ("Bob", None)), 3)]);
+}
+
+#[test]
+fn test_semi_and_anti_join() {
+    let users = StreamingHashMultiMapWithCount::new();
+    let follows = StreamingHashMultiMapWithCount::new();
+    let followers = follows.reversed();
+    users.insert("alice", "Alice");
+    users.insert("bob", "Bob");
+    follows.insert("alice", "bob");
+    let followed = users.semi_join(&*followers);
+    let not_followed = users.anti_join(&*followers);
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let mclone = messages.clone();
+    let _subscription = not_followed.listen(move |message| mclone.borrow_mut().push(message));
+    assert_eq!(followed.items(), vec![("bob", "Bob")]);
+    assert_eq!(not_followed.items(), vec![("alice", "Alice")]);
+    follows.insert("bob", "alice");
+    follows.insert("carol", "alice");
+    follows.remove("alice", "bob");
+    users.insert("dave", "Dave");
+    assert_eq!(followed.items(), vec![("alice", "Alice")]);
+    assert_eq!(*messages.borrow(), vec![MultiSetModifyMessage::RemoveOne(("alice", "Alice")),
+        MultiSetModifyMessage::InsertOne(("bob", "Bob")), MultiSetModifyMessage::InsertOne(("dave", "Dave"))]);
+    let mut items = not_followed.items();
+    items.sort();
+    assert_eq!(items, vec![("bob", "Bob"), ("dave", "Dave")]);
+}
+
+#[test]
+fn test_semi_join_only_sends_when_the_other_side_empties() {
+    let users = StreamingHashMultiMapWithCount::new();
+    let followers = StreamingHashMultiMapWithCount::new();
+    let followed = users.semi_join(&followers);
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let mclone = messages.clone();
+    let _subscription = followed.listen(move |message| mclone.borrow_mut().push(message));
+    users.add("bob", "Bob", 2);
+    followers.insert("bob", "alice");
+    followers.insert("bob", "carol");
+    followers.add("bob", "alice", 3);
+    followers.remove("bob", "carol");
+    assert_eq!(followed.weighted_items(), vec![(("bob", "Bob"), 2)]);
+    users.insert("bob", "Bob");
+    followers.remove_key(&"bob");
+    assert_eq!(*messages.borrow(), vec![MultiSetModifyMessage::Delta(("bob", "Bob"), 2),
+        MultiSetModifyMessage::InsertOne(("bob", "Bob")), MultiSetModifyMessage::Delta(("bob", "Bob"), -3)]);
+}
+
+#[test]
+fn test_semi_join_is_cleared_with_the_other_side() {
+    let users = StreamingHashMultiMapWithCount::new();
+    let followers = StreamingHashMultiMapWithCount::new();
+    let followed = users.semi_join(&followers);
+    users.add("alice", "Alice", 2);
+    users.insert("bob", "Bob");
+    followers.insert("alice", "bob");
+    followers.insert("bob", "alice");
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let mclone = messages.clone();
+    let _subscription = followed.listen(move |message| mclone.borrow_mut().push(message));
+    followers.clear();
+    assert_eq!(followed.items(), vec![]);
+    assert_eq!(messages.borrow().len(), 1);
+    assert!(matches!(messages.borrow()[0].first(), Some(MultiSetModifyMessage::Clear(items)) if items.len() == 2));
+    let mut removed = MultiSetModifyMessage::consolidate(messages.borrow().clone());
+    removed.sort();
+    assert_eq!(removed, vec![(("alice", "Alice"), -2), (("bob", "Bob"), -1)]);
+}
@@ -22,7 +22,7 @@ impl<'a, T> RcBorrow<'a, T> {
 impl<'a, T> Deref for RcBorrow<'a, T> {
     type Target = T;
     fn deref(&self) -> &T {
-        &**(self.rc.as_ref())
+        self.rc.as_ref()
     }
 }
 
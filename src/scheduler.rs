@@ -0,0 +1,142 @@
+use std::{cell::{Cell, RefCell}, cmp::Ordering, collections::BinaryHeap, rc::Rc};
+
+struct Task<'listener> {
+    rank: u32,
+    seq: u64,
+    run: Box<dyn FnOnce() + 'listener>
+}
+
+impl<'listener> PartialEq for Task<'listener> {
+    fn eq(&self, other: &Self)->bool {
+        (self.rank, self.seq)==(other.rank, other.seq)
+    }
+}
+
+impl<'listener> Eq for Task<'listener> {}
+
+impl<'listener> PartialOrd for Task<'listener> {
+    fn partial_cmp(&self, other: &Self)->Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'listener> Ord for Task<'listener> {
+    // BinaryHeap is a max-heap, the task with the lowest rank (and then the oldest) comes first.
+    fn cmp(&self, other: &Self)->Ordering {
+        (other.rank, other.seq).cmp(&(self.rank, self.seq))
+    }
+}
+
+/// Runs the work of the nodes of an operator graph in topological order.
+///
+/// Every node has a rank that is higher than the ranks of its sources.  Work scheduled while
+///   an update propagates is run lowest rank first, so when a node runs, all of its sources
+///   have already processed the update.
+///
+/// Each node starts with its own scheduler; connecting nodes with an operator merges their
+///   schedulers (union-find), so a graph ends up sharing one queue.
+pub struct Scheduler<'listener> {
+    parent: RefCell<Option<Rc<Scheduler<'listener>>>>,
+    queue: RefCell<BinaryHeap<Task<'listener>>>,
+    seq: Cell<u64>,
+    running: Cell<bool>
+}
+
+/// Clears the running flag even if a task panics, so the scheduler stays usable.
+struct RunGuard<'listener>(Rc<Scheduler<'listener>>);
+
+impl<'listener> Drop for RunGuard<'listener> {
+    fn drop(&mut self) {
+        self.0.root().running.set(false);
+    }
+}
+
+impl<'listener> Scheduler<'listener> {
+    pub fn new()->Rc<Self> {
+        Rc::new(Scheduler { parent: RefCell::new(None), queue: RefCell::new(BinaryHeap::new()),
+            seq: Cell::new(0), running: Cell::new(false) })
+    }
+
+    /// The scheduler that owns the queue of the graph.
+    pub fn root(self: &Rc<Self>)->Rc<Self> {
+        let parent=self.parent.borrow().clone();
+        match parent {
+            None => self.clone(),
+            Some(parent) => {
+                let root=parent.root();
+                *self.parent.borrow_mut()=Some(root.clone());
+                root
+            }
+        }
+    }
+
+    /// Merges the graphs of the two schedulers.
+    pub fn union(self: &Rc<Self>, other: &Rc<Self>) {
+        let root=self.root();
+        let other_root=other.root();
+        if Rc::ptr_eq(&root, &other_root) {
+            return;
+        }
+        let tasks: Vec<Task<'listener>>=other_root.queue.borrow_mut().drain().collect();
+        for mut task in tasks {
+            task.seq=root.next_seq();
+            root.queue.borrow_mut().push(task);
+        }
+        if other_root.running.get() {
+            root.running.set(true);
+        }
+        *other_root.parent.borrow_mut()=Some(root);
+    }
+
+    fn next_seq(&self)->u64 {
+        let seq=self.seq.get();
+        self.seq.set(seq+1);
+        seq
+    }
+
+    /// Queues work for a node of the given rank.  Call `run` to process the queue.
+    pub fn schedule(self: &Rc<Self>, rank: u32, run: impl FnOnce() + 'listener) {
+        let root=self.root();
+        let seq=root.next_seq();
+        root.queue.borrow_mut().push(Task { rank, seq, run: Box::new(run) });
+    }
+
+    /// Runs the queued work in rank order, unless the queue is already being processed
+    ///   further up the stack, in which case that loop picks up the new work.
+    pub fn run(self: &Rc<Self>) {
+        let root=self.root();
+        if root.running.replace(true) {
+            return;
+        }
+        let _guard=RunGuard(root);
+        loop {
+            let task=self.root().queue.borrow_mut().pop();
+            let Some(task)=task else {
+                break;
+            };
+            (task.run)();
+        }
+    }
+}
+
+#[test]
+fn test_scheduler_runs_lowest_rank_first() {
+    let order = Rc::new(RefCell::new(Vec::new()));
+    let scheduler = Scheduler::new();
+    let other = Scheduler::new();
+    for (s, rank, name) in [(&scheduler, 2, "c"), (&other, 0, "a"), (&scheduler, 1, "b")] {
+        let order = order.clone();
+        s.schedule(rank, move || order.borrow_mut().push(name));
+    }
+    scheduler.union(&other);
+    let order2 = order.clone();
+    let scheduler2 = scheduler.clone();
+    scheduler.schedule(1, move || {
+        order2.borrow_mut().push("b2");
+        let order3 = order2.clone();
+        scheduler2.schedule(1, move || order3.borrow_mut().push("b3"));
+        scheduler2.run();
+    });
+    other.run();
+    assert_eq!(*order.borrow(), vec!["a", "b", "b2", "b3", "c"]);
+}
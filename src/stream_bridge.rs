@@ -0,0 +1,161 @@
+use std::{pin::Pin, task::{Context, Poll}, hash::Hash};
+
+use futures::{Stream, StreamExt};
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::{message_listeners::{MessageListeners, Subscription}, multi_set::MultiSetModifyMessage,
+    queryable_streaming_multi_map::StreamingHashMultiMapWithCount, sync_message_listeners::{SyncMessageListeners, SyncSubscription},
+    sync_queryable_streaming_multi_map::SyncStreamingHashMultiMapWithCount};
+#[cfg(test)]
+use crate::{message_listeners::MessageListenersInterface, queryable_streaming_multi_map::QuerableStreamingMultiMap,
+    sync_message_listeners::SyncMessageListenersInterface, sync_queryable_streaming_multi_map::SyncQuerableStreamingMultiMap};
+
+/// What a `ListenerStream` does with a message when its channel is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// The message that doesn't fit is dropped, the stream continues with later messages.
+    DropNewest,
+    /// The stream ends after the buffered messages, so the consumer knows it lost messages
+    ///   and can resynchronize from the collection.
+    Close,
+}
+
+/// A `Stream` of the messages sent to a `MessageListeners`, buffered in a bounded tokio channel.
+///
+/// The stream is subscribed for as long as it lives; dropping it removes the listener.
+///
+/// Like the listeners, the stream is not `Send`: consume it on the thread of the collection,
+///   for example in a `tokio::task::LocalSet`.  Use `SyncListenerStream` to consume the
+///   messages of a sync collection from any thread.
+pub struct ListenerStream<'listener, M> {
+    receiver: mpsc::Receiver<M>,
+    _subscription: Subscription<'listener>
+}
+
+impl<'listener, M:Clone+'static> ListenerStream<'listener, M> {
+    pub fn new(listeners: &MessageListeners<'listener, M>, capacity: usize, overflow: OverflowPolicy)->Self {
+        let (sender, receiver)=mpsc::channel(capacity);
+        let subscription=listeners.listen(forward(sender, overflow));
+        Self { receiver, _subscription: subscription }
+    }
+}
+
+/// A listener that sends the messages to the channel, following the overflow policy.
+fn forward<M>(sender: mpsc::Sender<M>, overflow: OverflowPolicy)->impl FnMut(M) {
+    let mut sender=Some(sender);
+    move |message| {
+        let Some(s)=&sender else {
+            return;
+        };
+        match s.try_send(message) {
+            Ok(()) | Err(TrySendError::Closed(_)) => {},
+            Err(TrySendError::Full(_)) => {
+                if overflow==OverflowPolicy::Close {
+                    sender=None;
+                }
+            }
+        }
+    }
+}
+
+impl<'listener, M> Stream for ListenerStream<'listener, M> {
+    type Item = M;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>)->Poll<Option<M>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// A `Send` version of `ListenerStream` for `SyncMessageListeners`, so the messages of a sync
+///   collection can be consumed by a task on any worker thread.
+pub struct SyncListenerStream<M:Clone+Send+'static> {
+    receiver: mpsc::Receiver<M>,
+    _subscription: SyncSubscription
+}
+
+impl<M:Clone+Send+'static> SyncListenerStream<M> {
+    pub fn new(listeners: &SyncMessageListeners<M>, capacity: usize, overflow: OverflowPolicy)->Self {
+        let (sender, receiver)=mpsc::channel(capacity);
+        let subscription=listeners.listen(forward(sender, overflow));
+        Self { receiver, _subscription: subscription }
+    }
+}
+
+impl<M:Clone+Send+'static> Stream for SyncListenerStream<M> {
+    type Item = M;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>)->Poll<Option<M>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+impl<'a, K: Eq+Hash+Clone+'static, V: Eq+Hash+Clone+'static> StreamingHashMultiMapWithCount<'a, K, V> {
+    /// Applies every message of the stream to the map until the stream ends.
+    pub async fn apply_stream(&self, stream: impl Stream<Item=MultiSetModifyMessage<(K,V)>>) {
+        let mut stream=std::pin::pin!(stream);
+        while let Some(message)=stream.next().await {
+            self.apply(message);
+        }
+    }
+}
+
+impl<K: Eq+Hash+Clone+Send+Sync+'static, V: Eq+Hash+Clone+Send+Sync+'static> SyncStreamingHashMultiMapWithCount<K, V> {
+    /// Applies every message of the stream to the map until the stream ends.
+    pub async fn apply_stream(&self, stream: impl Stream<Item=MultiSetModifyMessage<(K,V)>>) {
+        let mut stream=std::pin::pin!(stream);
+        while let Some(message)=stream.next().await {
+            self.apply(message);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_listener_stream() {
+    let map = StreamingHashMultiMapWithCount::new();
+    let mut stream = map.stream(10, OverflowPolicy::DropNewest);
+    map.insert("key", "value");
+    map.remove("key", "value");
+    assert!(matches!(stream.next().await, Some(MultiSetModifyMessage::InsertOne(("key", "value")))));
+    assert!(matches!(stream.next().await, Some(MultiSetModifyMessage::RemoveOne(("key", "value")))));
+}
+
+#[tokio::test]
+async fn test_listener_stream_overflow() {
+    let ml = MessageListeners::new();
+    let mut dropping = ml.stream(2, OverflowPolicy::DropNewest);
+    let mut closing = ml.stream(2, OverflowPolicy::Close);
+    for i in 0..4 {
+        ml.send(i);
+    }
+    assert_eq!(dropping.by_ref().take(2).collect::<Vec<_>>().await, vec![0, 1]);
+    assert_eq!(closing.by_ref().take(2).collect::<Vec<_>>().await, vec![0, 1]);
+    ml.send(4);
+    drop(ml);
+    assert_eq!(dropping.collect::<Vec<_>>().await, vec![4]);
+    assert_eq!(closing.collect::<Vec<_>>().await, Vec::<i32>::new());
+}
+
+#[tokio::test]
+async fn test_apply_stream() {
+    let source = StreamingHashMultiMapWithCount::new();
+    let target = StreamingHashMultiMapWithCount::new();
+    let stream = source.stream(10, OverflowPolicy::DropNewest);
+    source.insert("key", "value");
+    source.insert("key", "value2");
+    source.remove("key", "value");
+    drop(source);
+    target.apply_stream(stream).await;
+    assert_eq!(target.get_one(&"key"), Some("value2"));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_sync_listener_stream() {
+    let source = SyncStreamingHashMultiMapWithCount::new();
+    let target = std::sync::Arc::new(SyncStreamingHashMultiMapWithCount::new());
+    let stream = source.stream(10, OverflowPolicy::DropNewest);
+    let tclone = target.clone();
+    let task = tokio::spawn(async move { tclone.apply_stream(stream.take(3)).await });
+    source.insert("key", "value");
+    source.insert("key", "value2");
+    source.remove("key", "value");
+    task.await.unwrap();
+    assert_eq!(target.get_one(&"key"), Some("value2"));
+}
@@ -0,0 +1,353 @@
+use std::{cell::RefCell, sync::{Arc, Condvar, Mutex, PoisonError, Weak}};
+
+use crate::stream_bridge::{OverflowPolicy, SyncListenerStream};
+
+type SyncListener<M> = Arc<Mutex<dyn FnMut(M) + Send>>;
+
+struct SyncSlot<M> {
+    generation: u64,
+    listener: Option<SyncListener<M>>
+}
+
+struct SyncListenersState<M> {
+    slots: Vec<SyncSlot<M>>,
+    free: Vec<usize>
+}
+
+trait CancelSyncListener: Send+Sync {
+    fn cancel(&self, index: usize, generation: u64);
+}
+
+impl<M> CancelSyncListener for Mutex<SyncListenersState<M>> {
+    fn cancel(&self, index: usize, generation: u64) {
+        let listener={
+            let mut state=self.lock().unwrap_or_else(PoisonError::into_inner);
+            let slot=&mut state.slots[index];
+            if slot.generation!=generation {
+                return;
+            }
+            slot.generation+=1;
+            let listener=slot.listener.take();
+            state.free.push(index);
+            listener
+        };
+        // The listener may hold subscriptions of its own, so it's dropped without the lock.
+        drop(listener);
+    }
+}
+
+/// A handle to a listener added with `SyncMessageListeners::listen`, the `Send` version of
+///   `Subscription`.
+///
+/// The listener is removed when the subscription is dropped, unless `detach` is called, in
+///   which case it lives as long as the `SyncMessageListeners` it was added to.
+#[must_use = "dropping a SyncSubscription removes the listener immediately"]
+pub struct SyncSubscription {
+    state: Option<Weak<dyn CancelSyncListener>>,
+    index: usize,
+    generation: u64
+}
+
+impl SyncSubscription {
+    /// Keeps the listener subscribed for the lifetime of the `SyncMessageListeners`.
+    pub fn detach(mut self) {
+        self.state=None;
+    }
+
+    /// Removes the listener, same as dropping the subscription.
+    pub fn cancel(self) {}
+}
+
+impl Drop for SyncSubscription {
+    fn drop(&mut self) {
+        if let Some(state)=self.state.take().and_then(|state| state.upgrade()) {
+            state.cancel(self.index, self.generation);
+        }
+    }
+}
+
+/// A thread-safe version of `MessageListeners`.
+///
+/// The listener vector is shared behind an `Arc`, so cloning a `SyncMessageListeners` gives
+/// another handle to the same listeners.  `send` takes a snapshot of the listeners and calls
+/// them without holding the lock of the vector, so a listener may subscribe or cancel listeners
+/// of the same `SyncMessageListeners` (a listener that is cancelled during a send may still get
+/// that message).  A listener must not send to the listeners it is subscribed to.
+///
+/// Every `SyncMessageListeners` belongs to a graph, see `GraphLock`: operators built on
+///   top of it join its graph.
+pub struct SyncMessageListeners<M> {
+    listeners: Arc<Mutex<SyncListenersState<M>>>,
+    graph_lock: GraphLock
+}
+
+impl<M> Clone for SyncMessageListeners<M> {
+    fn clone(&self)->Self {
+        Self { listeners: self.listeners.clone(), graph_lock: self.graph_lock.clone() }
+    }
+}
+
+impl<M:Clone+Send+'static> Default for SyncMessageListeners<M> {
+    fn default()->Self {
+        Self::new()
+    }
+}
+
+impl<M:Clone+Send+'static> SyncMessageListeners<M> {
+    /// Listeners in a new graph.
+    pub fn new()->Self {
+        Self::in_graph(&GraphLock::new())
+    }
+
+    /// Listeners in the graph of `graph_lock`.
+    pub fn in_graph(graph_lock: &GraphLock)->Self {
+        let state=SyncListenersState { slots: Vec::new(), free: Vec::new() };
+        SyncMessageListeners { listeners: Arc::new(Mutex::new(state)), graph_lock: graph_lock.clone() }
+    }
+
+    pub fn graph_lock(&self)->&GraphLock {
+        &self.graph_lock
+    }
+
+    /// This method takes a function object and adds it to the vector of listeners.
+    ///
+    /// Slots of cancelled listeners are reused.
+    pub fn listen(&self, f: impl FnMut(M)+Send+'static)->SyncSubscription {
+        let listener: SyncListener<M>=Arc::new(Mutex::new(f));
+        let (index, generation)={
+            let mut state=self.listeners.lock().unwrap_or_else(PoisonError::into_inner);
+            let index=match state.free.pop() {
+                Some(index)=>{
+                    state.slots[index].listener=Some(listener);
+                    index
+                },
+                None=>{
+                    state.slots.push(SyncSlot { generation: 0, listener: Some(listener) });
+                    state.slots.len()-1
+                }
+            };
+            (index, state.slots[index].generation)
+        };
+        let state: Arc<dyn CancelSyncListener>=self.listeners.clone();
+        SyncSubscription { state: Some(Arc::downgrade(&state)), index, generation }
+    }
+
+    /// This method sends a message to all listeners in the vector.
+    ///
+    /// A listener that panicked before keeps getting messages.
+    pub fn send(&self, message: M) {
+        let listeners: Vec<SyncListener<M>>=self.listeners.lock().unwrap_or_else(PoisonError::into_inner)
+            .slots.iter().filter_map(|slot| slot.listener.clone()).collect();
+        for listener in listeners {
+            (*listener.lock().unwrap_or_else(PoisonError::into_inner))(message.clone());
+        }
+    }
+}
+
+/// Serializes the writes to the collections of one graph.
+///
+/// A join reads one of its sources while propagating a change of the other, so two writers
+///   propagating at the same time could both see each other's change and emit the same pair
+///   twice.  Collections that are connected by operators share a lock (a join merges the
+///   graphs of its sources), while unrelated collections are written in parallel.  Writes
+///   coming from listeners already hold the lock of their graph.
+#[derive(Clone)]
+pub struct GraphLock(Arc<GraphLockState>);
+
+struct GraphLockState {
+    locked: Mutex<bool>,
+    unlocked: Condvar,
+    /// The lock this one was merged into, it has to be followed to find the lock of the graph.
+    merged_into: Mutex<Option<GraphLock>>
+}
+
+thread_local! {
+    /// The graph locks held by this thread.
+    static HELD_GRAPH_LOCKS: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+impl Default for GraphLock {
+    fn default()->Self {
+        Self::new()
+    }
+}
+
+impl GraphLock {
+    pub fn new()->Self {
+        GraphLock(Arc::new(GraphLockState { locked: Mutex::new(false), unlocked: Condvar::new(), merged_into: Mutex::new(None) }))
+    }
+
+    fn root(&self)->GraphLock {
+        let mut r=self.clone();
+        loop {
+            let next=r.0.merged_into.lock().unwrap_or_else(PoisonError::into_inner).clone();
+            match next {
+                Some(next)=>r=next,
+                None=>return r
+            }
+        }
+    }
+
+    fn id(&self)->usize {
+        Arc::as_ptr(&self.0) as usize
+    }
+
+    /// Waits until no other thread writes to the graph; returns a guard that doesn't unlock
+    ///   anything if this thread already holds the lock.
+    pub fn write(&self)->WriteGuard {
+        loop {
+            let root=self.root();
+            if HELD_GRAPH_LOCKS.with(|held| held.borrow().contains(&root.id())) {
+                return WriteGuard(None);
+            }
+            {
+                let mut locked=root.0.locked.lock().unwrap_or_else(PoisonError::into_inner);
+                while *locked {
+                    locked=root.0.unlocked.wait(locked).unwrap_or_else(PoisonError::into_inner);
+                }
+                *locked=true;
+            }
+            let guard=WriteGuard(Some(root.clone()));
+            // The graph may have been merged into another one while waiting.
+            if root.0.merged_into.lock().unwrap_or_else(PoisonError::into_inner).is_none() {
+                HELD_GRAPH_LOCKS.with(|held| held.borrow_mut().push(root.id()));
+                return guard;
+            }
+        }
+    }
+
+    /// Makes the two graphs one, waiting for the writes in progress in both of them.
+    pub fn merge(&self, other: &GraphLock) {
+        loop {
+            let (root, other_root)=(self.root(), other.root());
+            if root.id()==other_root.id() {
+                return;
+            }
+            // Locking in a fixed order, so two merges can't wait for each other.
+            let (first, second)=if root.id()<other_root.id() { (&root, &other_root) } else { (&other_root, &root) };
+            let _first=first.write();
+            let _second=second.write();
+            if first.root().id()==first.id() && second.root().id()==second.id() {
+                *other_root.0.merged_into.lock().unwrap_or_else(PoisonError::into_inner)=Some(root.clone());
+                return;
+            }
+        }
+    }
+}
+
+/// Holds the lock of a graph, see `GraphLock::write`.
+pub struct WriteGuard(Option<GraphLock>);
+
+impl Drop for WriteGuard {
+    fn drop(&mut self) {
+        if let Some(lock)=self.0.take() {
+            HELD_GRAPH_LOCKS.with(|held| held.borrow_mut().retain(|id| *id!=lock.id()));
+            *lock.0.locked.lock().unwrap_or_else(PoisonError::into_inner)=false;
+            lock.0.unlocked.notify_all();
+        }
+    }
+}
+
+pub trait SyncMessageListenersInterface<M:Clone+Send+'static> : Sized {
+    fn listeners(&self)->&SyncMessageListeners<M>;
+    fn listen(&self, f: impl FnMut(M)+Send+'static)->SyncSubscription {
+        SyncMessageListeners::listen(self.listeners(), f)
+    }
+    fn map<M2:Clone+Send+'static>(&self, f: impl Fn(M)->M2+Send+'static)->Arc<SyncMessageListeners<M2>> {
+        let r = Arc::new(SyncMessageListeners::in_graph(self.listeners().graph_lock()));
+        let rclone=r.clone();
+        self.listen(move |m| rclone.send(f(m))).detach();
+        r
+    }
+    fn filter(&self, f: impl Fn(&M)->bool+Send+'static)->Arc<SyncMessageListeners<M>> {
+        let r = Arc::new(SyncMessageListeners::in_graph(self.listeners().graph_lock()));
+        let rclone=r.clone();
+        self.listen(move |m| if f(&m) { rclone.send(m)}).detach();
+        r
+    }
+    /// Returns a `Send` stream of the messages, see `SyncListenerStream`.
+    fn stream(&self, capacity: usize, overflow: OverflowPolicy)->SyncListenerStream<M> {
+        SyncListenerStream::new(self.listeners(), capacity, overflow)
+    }
+}
+
+impl<M:Clone+Send+'static> SyncMessageListenersInterface<M> for SyncMessageListeners<M> {
+    fn listeners(&self)->&SyncMessageListeners<M> {
+        self
+    }
+}
+
+#[test]
+fn test_sync_message_listeners() {
+    let messages = Arc::new(Mutex::new(Vec::new()));
+    let ml = SyncMessageListeners::new();
+    let mclone = messages.clone();
+    let _subscription = ml.listen(move |m: i32| mclone.lock().unwrap().push(m));
+    let handles: Vec<_> = (0..4).map(|i| {
+        let ml = ml.clone();
+        std::thread::spawn(move || ml.send(i))
+    }).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let mut messages = messages.lock().unwrap().clone();
+    messages.sort();
+    assert_eq!(messages, vec![0, 1, 2, 3]);
+}
+
+#[test]
+fn test_sync_message_listeners_map_filter() {
+    let messages = Arc::new(Mutex::new(Vec::new()));
+    let ml = SyncMessageListeners::new();
+    let mclone = messages.clone();
+    let mapped = ml.map(|m: i32| m * 2);
+    let filtered = mapped.filter(|m| *m > 2);
+    let _subscription = filtered.listen(move |m| mclone.lock().unwrap().push(m));
+    ml.send(1);
+    ml.send(2);
+    ml.send(3);
+    assert_eq!(*messages.lock().unwrap(), vec![4, 6]);
+}
+
+#[test]
+fn test_sync_message_listeners_subscribe_from_listener() {
+    let messages = Arc::new(Mutex::new(Vec::new()));
+    let ml = SyncMessageListeners::new();
+    let (mlclone, mclone) = (ml.clone(), messages.clone());
+    let first = Arc::new(Mutex::new(None));
+    let second = Arc::new(Mutex::new(None));
+    let (fclone, sclone) = (first.clone(), second.clone());
+    *first.lock().unwrap() = Some(ml.listen(move |m: i32| {
+        fclone.lock().unwrap().take();
+        mclone.lock().unwrap().push(m * 10);
+        let mclone = mclone.clone();
+        *sclone.lock().unwrap() = Some(mlclone.listen(move |m| mclone.lock().unwrap().push(m)));
+    }));
+    ml.send(1);
+    ml.send(2);
+    assert_eq!(*messages.lock().unwrap(), vec![10, 2]);
+}
+
+#[test]
+fn test_sync_message_listeners_reuse_cancelled_slots() {
+    let messages = Arc::new(Mutex::new(Vec::new()));
+    let ml = SyncMessageListeners::new();
+    let first = ml.listen(|_: i32| {});
+    let mclone = messages.clone();
+    let _second = ml.listen(move |m| mclone.lock().unwrap().push(m));
+    let first_id = (first.index, first.generation);
+    drop(first);
+    for _ in 0..10 {
+        let _subscription = ml.listen(|_| {});
+    }
+    assert_eq!(ml.listeners.lock().unwrap().slots.len(), 2);
+    let mclone = messages.clone();
+    let third = ml.listen(move |m| mclone.lock().unwrap().push(m * 10));
+    assert_eq!(third.index, first_id.0);
+    // A stale cancel of the slot doesn't remove the listener that reused it.
+    ml.listeners.cancel(first_id.0, first_id.1);
+    ml.send(1);
+    drop(third);
+    ml.send(2);
+    assert_eq!(*messages.lock().unwrap(), vec![10, 1, 2]);
+}
@@ -0,0 +1,646 @@
+use std::{collections::{HashSet, HashMap}, sync::{Arc, RwLock}, marker::PhantomData};
+
+use crate::{multi_set::{MultiSetModifyMessage, SyncMultiSetMessageListeners}, sync_message_listeners::{GraphLock, SyncMessageListenersInterface, SyncMessageListeners, SyncSubscription}};
+use std::hash::Hash;
+
+/// Thread-safe version of `QuerableStreamingMultiMapGetter`.
+pub trait SyncQuerableStreamingMultiMapGetter<K:Eq+Hash+Clone+Send+Sync+'static,V:Eq+Hash+Clone+Send+Sync+'static> : Send+Sync {
+    fn get(&self, key: &K)->HashSet<V>;
+    fn items(&self)->Vec<(K, V)>;
+    fn get_one(&self, key: &K)->Option<V> {
+        let set=self.get(key);
+        if set.len()!=1 {
+            None
+        } else {
+            Some(set.iter().next().unwrap().clone())
+        }
+    }
+    /// Returns the values of the key with their multiplicities, see
+    ///   `QuerableStreamingMultiMapGetter::counts`.
+    fn counts(&self, key: &K)->HashMap<V, u64> {
+        self.get(key).into_iter().map(|v| (v, 1)).collect()
+    }
+    /// Returns every key value pair with its multiplicity.
+    fn weighted_items(&self)->Vec<((K, V), u64)> {
+        self.items().into_iter().map(|pair| (pair, 1)).collect()
+    }
+}
+
+/// Thread-safe version of `QuerableStreamingMultiMap`.
+///
+/// Getters are shared through `Arc`, and every node is `Send + Sync`, so a collection can be
+///   written to from any worker thread of a multi-threaded tokio runtime.
+pub trait SyncQuerableStreamingMultiMap<K:Eq+Hash+Clone+Send+Sync+'static,V:Eq+Hash+Clone+Send+Sync+'static> :
+     SyncMessageListenersInterface<MultiSetModifyMessage<(K,V)>> {
+    type Getter : SyncQuerableStreamingMultiMapGetter<K,V> + 'static;
+    fn getter(&self)->&Arc<Self::Getter>;
+    fn get(&self, key: &K)->HashSet<V> {
+        self.getter().get(key)
+    }
+    fn get_one(&self, key: &K)->Option<V> {
+        self.getter().get_one(key)
+    }
+    fn items(&self)->Vec<(K, V)> {
+        self.getter().items()
+    }
+    fn weighted_items(&self)->Vec<((K, V), u64)> {
+        self.getter().weighted_items()
+    }
+    fn filter_item<Allow: Fn(K,V)->bool+Send+Sync+'static>(&self, allow: Allow)->
+            SyncFilterQuerableStreamingMultiMap<K, V, Self::Getter, Allow> {
+        SyncFilterQuerableStreamingMultiMap::new(self, allow)
+    }
+    fn map<T2:Clone+Send+'static>(&self, f: impl Fn(K, V)->T2+Send+'static)->
+            Arc<SyncMultiSetMessageListeners<T2>> {
+        self.listeners().map_items(move |(k, v)| f(k, v))
+    }
+    fn join<V2:Eq+Hash+Clone+Send+Sync+'static, Source2: SyncQuerableStreamingMultiMap<K, V2>>(
+            &self, other: &Source2)->
+            SyncJoinQuerableStreamingMultiMap<K, V, V2, Self::Getter, Source2::Getter> {
+        SyncJoinQuerableStreamingMultiMap::new(self, other)
+    }
+    fn reversed(&self)->Arc<SyncStreamingHashMultiMapWithCount<V, K>> {
+        self.group_by(|k, v| (v, k))
+    }
+    /// Like `QuerableStreamingMultiMap::group_by`, the new map starts with the current contents.
+    fn group_by<K2: Eq+Hash+Clone+Send+Sync+'static, V2: Eq+Hash+Clone+Send+Sync+'static>(
+            &self, f:impl Fn(K, V)->(K2, V2)+Send+Sync+'static) ->
+            Arc<SyncStreamingHashMultiMapWithCount<K2,V2>> {
+        let f=Arc::new(f);
+        let fclone=f.clone();
+        let r=self.listeners().group_by(move |k, v| fclone(k, v));
+        // Writes that finished before the lock was taken were sent to the new map, but they
+        //   are part of the contents as well, so the contents replace them.
+        let _write=self.listeners().graph_lock().write();
+        r.load(self.weighted_items().into_iter().map(|((k, v), count)| (f(k, v), count)));
+        r
+    }
+}
+
+type SyncMultiMapData<K, V> = RwLock<HashMap<K, HashMap<V, u64>>>;
+
+/// Thread-safe version of `StreamingHashMultiMapWithCount`.
+///
+/// Writers of the same graph (see `GraphLock`) are serialized, so a change is propagated through
+///   the whole graph before the next one starts, but the data lock is released before listeners are called, so listeners may
+///   query any collection (including this one).
+pub struct SyncStreamingHashMultiMapWithCount<K: Eq+Hash+Clone+Send+Sync+'static, V: Eq+Hash+Clone+Send+Sync+'static> {
+    listeners: SyncMultiSetMessageListeners<(K, V)>,
+    data: Arc<SyncMultiMapData<K, V>>
+}
+
+impl<K: Eq+Hash+Clone+Send+Sync+'static, V: Eq+Hash+Clone+Send+Sync+'static> SyncStreamingHashMultiMapWithCount<K, V> {
+    pub fn new()->Self {
+        Self::in_graph(&GraphLock::new())
+    }
+    /// A map that is written under the lock of an existing graph.
+    pub fn in_graph(graph_lock: &GraphLock)->Self {
+        Self {listeners: SyncMultiSetMessageListeners::in_graph(graph_lock), data: Arc::new(RwLock::new(HashMap::new()))}
+    }
+    /// Replaces the contents without notifying the listeners, for a map nobody listens to yet.
+    fn load(&self, items: impl Iterator<Item=((K, V), u64)>) {
+        let mut data=self.data.write().unwrap();
+        data.clear();
+        for ((k, v), count) in items {
+            *data.entry(k).or_default().entry(v).or_insert(0)+=count;
+        }
+    }
+}
+
+impl<K: Eq+Hash+Clone+Send+Sync+'static, V: Eq+Hash+Clone+Send+Sync+'static> Default for SyncStreamingHashMultiMapWithCount<K, V> {
+    fn default()->Self {
+        Self::new()
+    }
+}
+
+impl<K: Eq+Hash+Clone+Send+Sync+'static, V: Eq+Hash+Clone+Send+Sync+'static>
+    SyncQuerableStreamingMultiMapGetter<K,V> for SyncMultiMapData<K, V> {
+    fn get(&self, key: &K)->HashSet<V> {
+        let data = self.read().unwrap();
+        match data.get(key) {
+            None => HashSet::new(),
+            Some(values) => values.keys().cloned().collect()
+        }
+    }
+    fn get_one(&self, key: &K)->Option<V> {
+        let data = self.read().unwrap();
+        match data.get(key) {
+            Some(values) if values.len()==1 => values.keys().next().cloned(),
+            _ => None
+        }
+    }
+    fn items(&self)->Vec<(K, V)> {
+        self.read().unwrap().iter().flat_map(|(k, values)| values.keys().map(|v| (k.clone(), v.clone()))).collect()
+    }
+    fn counts(&self, key: &K)->HashMap<V, u64> {
+        self.read().unwrap().get(key).cloned().unwrap_or_default()
+    }
+    fn weighted_items(&self)->Vec<((K, V), u64)> {
+        self.read().unwrap().iter().flat_map(|(k, values)| values.iter().map(|(v, count)| ((k.clone(), v.clone()), *count))).collect()
+    }
+}
+
+impl<K: Eq+Hash+Clone+Send+Sync+'static, V: Eq+Hash+Clone+Send+Sync+'static>
+     SyncQuerableStreamingMultiMap<K,V> for SyncStreamingHashMultiMapWithCount<K, V> {
+    type Getter = SyncMultiMapData<K, V>;
+    fn getter(&self)->&Arc<Self::Getter> {
+        &self.data
+    }
+}
+
+impl<K: Eq+Hash+Clone+Send+Sync+'static, V: Eq+Hash+Clone+Send+Sync+'static>
+    SyncMessageListenersInterface<MultiSetModifyMessage<(K,V)>> for SyncStreamingHashMultiMapWithCount<K, V> {
+    fn listeners(&self)->&SyncMessageListeners<MultiSetModifyMessage<(K,V)>> {
+        &self.listeners
+    }
+}
+
+impl<K: Eq+Hash+Clone+Send+Sync+'static, V: Eq+Hash+Clone+Send+Sync+'static> SyncStreamingHashMultiMapWithCount<K, V> {
+    pub fn insert(&self, key: K, value: V) {
+        let _write=self.listeners.graph_lock().write();
+        *self.data.write().unwrap().entry(key.clone()).or_default().entry(value.clone()).or_insert(0)+=1;
+        self.listeners.send(MultiSetModifyMessage::InsertOne((key, value)));
+    }
+    /// Removes one occurrence of the pair, returns false if it wasn't present.
+    pub fn remove(&self, key: K, value: V)->bool {
+        let _write=self.listeners.graph_lock().write();
+        {
+            let mut data = self.data.write().unwrap();
+            let Some(value_hash_map)=data.get_mut(&key) else {
+                return false;
+            };
+            let Some(count)=value_hash_map.get_mut(&value) else {
+                return false;
+            };
+            *count-=1;
+            if *count==0 {
+                value_hash_map.remove(&value);
+                if value_hash_map.is_empty() {
+                    data.remove(&key);
+                }
+            }
+        }
+        self.listeners.send(MultiSetModifyMessage::RemoveOne((key, value)));
+        true
+    }
+    /// Changes the multiplicity of the pair by `diff`, without going below 0.
+    pub fn add(&self, key: K, value: V, diff: i64) {
+        self.apply(MultiSetModifyMessage::Delta((key, value), diff));
+    }
+    /// Removes every pair, sending a `Clear` message, see `MultiSetModifyMessage::remove_all`.
+    pub fn clear(&self) {
+        let _write=self.listeners.graph_lock().write();
+        let data=std::mem::take(&mut *self.data.write().unwrap());
+        let removed=data.into_iter().flat_map(|(k, values)| values.into_iter().map(move |(v, count)| ((k.clone(), v), count))).collect();
+        if let Some(message)=MultiSetModifyMessage::remove_all(removed, MultiSetModifyMessage::Clear) {
+            self.listeners.send(message);
+        }
+    }
+    /// Removes every value of the key, sending a `ClearKey` message.  Returns false if the key
+    ///   had no values.
+    pub fn remove_key(&self, key: &K)->bool {
+        let _write=self.listeners.graph_lock().write();
+        let values=self.data.write().unwrap().remove(key);
+        let Some(values)=values else {
+            return false;
+        };
+        let removed=values.into_iter().map(|(v, count)| ((key.clone(), v), count)).collect();
+        if let Some(message)=MultiSetModifyMessage::remove_all(removed, MultiSetModifyMessage::ClearKey) {
+            self.listeners.send(message);
+        }
+        true
+    }
+    /// Removes the pairs for which `f` returns false, in one batch.
+    pub fn retain(&self, f: impl Fn(&K, &V)->bool) {
+        let _write=self.listeners.graph_lock().write();
+        let removed: Vec<_>=self.data.read().unwrap().iter().flat_map(|(k, values)| values.iter()
+            .filter(|(v, _)| !f(k, v)).map(|(v, count)| MultiSetModifyMessage::Delta((k.clone(), v.clone()), -(*count as i64)))
+            .collect::<Vec<_>>()).collect();
+        if let Some(message)=MultiSetModifyMessage::from_messages(removed) {
+            self.apply(message);
+        }
+    }
+    /// Applies a message to the map atomically, like a transaction of
+    ///   `StreamingHashMultiMapWithCount`: the listeners get a single (batch) message with the
+    ///   changes of the multiplicities, replacements are sent as `Replace`.  A `Clear` removes
+    ///   only the listed pairs, see `MultiSetModifyMessage::Clear`.
+    pub fn apply(&self, message: MultiSetModifyMessage<(K, V)>) {
+        let _write=self.listeners.graph_lock().write();
+        let message=message.apply_to(&mut *self.data.write().unwrap());
+        if let Some(message)=message {
+            self.listeners.send(message);
+        }
+    }
+    /// Replaces all values of the key with the value in one batch; if the key had a single
+    ///   other value, the change is sent as a `Replace`.
+    pub fn set(&self, key: K, value: V) {
+        let _write=self.listeners.graph_lock().write();
+        let old: Vec<V>=self.get(&key).into_iter().collect();
+        let message=if old.len()==1 && old[0]!=value {
+            MultiSetModifyMessage::Replace { old: (key.clone(), old[0].clone()), new: (key, value) }
+        } else {
+            let mut messages: Vec<_>=old.into_iter().map(|v| MultiSetModifyMessage::RemoveOne((key.clone(), v))).collect();
+            messages.push(MultiSetModifyMessage::InsertOne((key, value)));
+            MultiSetModifyMessage::Batch(messages)
+        };
+        self.apply(message);
+    }
+}
+
+pub struct SyncFilterQuerableStreamingMultiMap<K:Eq+Hash+Clone+Send+Sync+'static,V:Eq+Hash+Clone+Send+Sync+'static,
+    SourceGetter: SyncQuerableStreamingMultiMapGetter<K,V>,
+    Allow: Fn(K, V)->bool+Send+Sync+'static> {
+    listeners: SyncMultiSetMessageListeners<(K, V)>,
+    _source_subscription: SyncSubscription,
+    getter: Arc<SyncFilterQuerableStreamingMultiMapGetter<K,V, SourceGetter, Allow>>
+}
+
+pub struct SyncFilterQuerableStreamingMultiMapGetter<K:Eq+Hash+Clone+Send+Sync+'static,V:Eq+Hash+Clone+Send+Sync+'static,
+        SourceGetter: SyncQuerableStreamingMultiMapGetter<K,V>,
+        Allow: Fn(K, V)->bool+Send+Sync> {
+    source: Arc<SourceGetter>,
+    allow: Arc<Allow>,
+    phantom_data: PhantomData<(K,V)>
+}
+
+impl <K:Eq+Hash+Clone+Send+Sync+'static,V:Eq+Hash+Clone+Send+Sync+'static,
+        Getter: SyncQuerableStreamingMultiMapGetter<K,V>,
+        Allow: Fn(K, V)->bool+Send+Sync>
+    SyncQuerableStreamingMultiMapGetter<K,V>
+            for SyncFilterQuerableStreamingMultiMapGetter<K,V, Getter, Allow> {
+    fn get(&self, key: &K)->HashSet<V> {
+        let values=self.source.get(key);
+        let allow=&self.allow;
+        values.into_iter().filter(|v| allow(key.clone(), v.clone())).collect()
+    }
+    fn items(&self)->Vec<(K, V)> {
+        self.source.items().into_iter().filter(|(k, v)| (self.allow)(k.clone(), v.clone())).collect()
+    }
+    fn counts(&self, key: &K)->HashMap<V, u64> {
+        self.source.counts(key).into_iter().filter(|(v, _)| (self.allow)(key.clone(), v.clone())).collect()
+    }
+    fn weighted_items(&self)->Vec<((K, V), u64)> {
+        self.source.weighted_items().into_iter().filter(|((k, v), _)| (self.allow)(k.clone(), v.clone())).collect()
+    }
+}
+
+impl<K:Eq+Hash+Clone+Send+Sync+'static,V:Eq+Hash+Clone+Send+Sync+'static,
+        SourceGetter: SyncQuerableStreamingMultiMapGetter<K,V>+'static,
+        Allow: Fn(K, V)->bool+Send+Sync+'static>
+    SyncFilterQuerableStreamingMultiMap<K,V, SourceGetter, Allow> {
+    pub fn new<Source: SyncQuerableStreamingMultiMap<K,V,Getter=SourceGetter>>(source: &Source, allow: Allow)->Self {
+        let allow=Arc::new(allow);
+        let listeners=SyncMultiSetMessageListeners::in_graph(source.listeners().graph_lock());
+        let lclone=listeners.clone();
+        let aclone=allow.clone();
+        let source_subscription=source.listeners().listen(move |message: MultiSetModifyMessage<(K,V)>| {
+            if let Some(message)=message.filter_items(&|(key, value)| (aclone)(key.clone(), value.clone())) {
+                lclone.send(message);
+            }
+        });
+        Self {
+            listeners,
+            _source_subscription: source_subscription,
+            getter: Arc::new(SyncFilterQuerableStreamingMultiMapGetter {
+                source: source.getter().clone(),
+                allow,
+                phantom_data: PhantomData
+            })
+        }
+    }
+}
+
+impl<K:Eq+Hash+Clone+Send+Sync+'static,V:Eq+Hash+Clone+Send+Sync+'static,
+        SourceGetter: SyncQuerableStreamingMultiMapGetter<K,V>+'static,
+        Allow: Fn(K, V)->bool+Send+Sync+'static>
+    SyncQuerableStreamingMultiMap<K,V> for SyncFilterQuerableStreamingMultiMap<K,V, SourceGetter, Allow> {
+    type Getter=SyncFilterQuerableStreamingMultiMapGetter<K,V, SourceGetter, Allow>;
+    fn getter(&self)->&Arc<Self::Getter> {
+        &self.getter
+    }
+}
+
+impl<K:Eq+Hash+Clone+Send+Sync+'static,V:Eq+Hash+Clone+Send+Sync+'static,
+        SourceGetter: SyncQuerableStreamingMultiMapGetter<K,V>,
+        Allow: Fn(K, V)->bool+Send+Sync+'static>
+    SyncMessageListenersInterface<MultiSetModifyMessage<(K,V)>> for SyncFilterQuerableStreamingMultiMap<K,V, SourceGetter, Allow> {
+    fn listeners(&self)->&SyncMessageListeners<MultiSetModifyMessage<(K,V)>> {
+        &self.listeners
+    }
+}
+
+pub struct SyncJoinQuerableStreamingMultiMap<K:Eq+Hash+Clone+Send+Sync+'static,V:Eq+Hash+Clone+Send+Sync+'static, V2:Eq+Hash+Clone+Send+Sync+'static,
+    SourceGetter: SyncQuerableStreamingMultiMapGetter<K,V>,
+    SourceGetter2: SyncQuerableStreamingMultiMapGetter<K,V2>> {
+    listeners: SyncMultiSetMessageListeners<(K, (V, V2))>,
+    _source_subscription: SyncSubscription,
+    _source2_subscription: SyncSubscription,
+    getter: Arc<SyncJoinQuerableStreamingMultiMapGetter<K,V,V2, SourceGetter, SourceGetter2>>
+}
+
+pub struct SyncJoinQuerableStreamingMultiMapGetter<K:Eq+Hash+Clone+Send+Sync+'static,
+    V:Eq+Hash+Clone+Send+Sync+'static, V2:Eq+Hash+Clone+Send+Sync+'static,
+    SourceGetter: SyncQuerableStreamingMultiMapGetter<K,V>,
+    SourceGetter2: SyncQuerableStreamingMultiMapGetter<K,V2>> {
+    source: Arc<SourceGetter>,
+    source2: Arc<SourceGetter2>,
+    phantom_data: PhantomData<(K,V,V2)>
+}
+
+impl <K:Eq+Hash+Clone+Send+Sync+'static,V:Eq+Hash+Clone+Send+Sync+'static, V2:Eq+Hash+Clone+Send+Sync+'static,
+    Getter: SyncQuerableStreamingMultiMapGetter<K,V>,
+    Getter2: SyncQuerableStreamingMultiMapGetter<K,V2>>
+    SyncQuerableStreamingMultiMapGetter<K,(V, V2)>
+    for SyncJoinQuerableStreamingMultiMapGetter<K,V, V2, Getter, Getter2> {
+    fn get(&self, key: &K)->HashSet<(V, V2)> {
+        let source_values=self.source.get(key);
+        let source_values2=self.source2.get(key);
+        let mut r=HashSet::new();
+        for v in &source_values {
+            for v2 in &source_values2 {
+                r.insert((v.clone(), v2.clone()));
+            }
+        }
+        r
+    }
+    fn items(&self)->Vec<(K, (V, V2))> {
+        let mut r=Vec::new();
+        for (k, v) in self.source.items() {
+            for v2 in self.source2.get(&k) {
+                r.push((k.clone(), (v.clone(), v2)));
+            }
+        }
+        r
+    }
+    fn counts(&self, key: &K)->HashMap<(V, V2), u64> {
+        let counts2=self.source2.counts(key);
+        self.source.counts(key).into_iter().flat_map(|(v, count)| counts2.iter()
+            .map(move |(v2, count2)| ((v.clone(), v2.clone()), count*count2))).collect()
+    }
+    fn weighted_items(&self)->Vec<((K, (V, V2)), u64)> {
+        let mut r=Vec::new();
+        for ((k, v), count) in self.source.weighted_items() {
+            for (v2, count2) in self.source2.counts(&k) {
+                r.push(((k.clone(), (v.clone(), v2)), count*count2));
+            }
+        }
+        r
+    }
+}
+
+impl<K:Eq+Hash+Clone+Send+Sync+'static,V:Eq+Hash+Clone+Send+Sync+'static, V2:Eq+Hash+Clone+Send+Sync+'static,
+    SourceGetter: SyncQuerableStreamingMultiMapGetter<K,V>+'static,
+    SourceGetter2: SyncQuerableStreamingMultiMapGetter<K,V2>+'static>
+    SyncJoinQuerableStreamingMultiMap<K,V, V2, SourceGetter, SourceGetter2> {
+    pub fn new<Source: SyncQuerableStreamingMultiMap<K,V,Getter=SourceGetter>,
+            Source2: SyncQuerableStreamingMultiMap<K,V2,Getter=SourceGetter2>>(source: &Source, source2: &Source2)->Self {
+        source.listeners().graph_lock().merge(source2.listeners().graph_lock());
+        let listeners=SyncMultiSetMessageListeners::in_graph(source.listeners().graph_lock());
+        let getter=Arc::new(SyncJoinQuerableStreamingMultiMapGetter {
+            source: source.getter().clone(),
+            source2: source2.getter().clone(),
+            phantom_data: PhantomData
+        });
+
+        let rlisteners=listeners.clone();
+        let csource2=getter.source2.clone();
+        let source_subscription=source.listeners().listen(move |message: MultiSetModifyMessage<(K,V)>| {
+            let joined=message.flat_map_weighted(&|(key, value)| csource2.counts(&key).into_iter()
+                .map(|(value2, count2)| ((key.clone(), (value.clone(), value2)), count2 as i64)).collect());
+            if let Some(joined)=joined {
+                rlisteners.send(joined);
+            }
+        });
+
+        let rlisteners=listeners.clone();
+        let csource=getter.source.clone();
+        let source2_subscription=source2.listeners().listen(move |message: MultiSetModifyMessage<(K,V2)>| {
+            let joined=message.flat_map_weighted(&|(key, value2)| csource.counts(&key).into_iter()
+                .map(|(value, count)| ((key.clone(), (value, value2.clone())), count as i64)).collect());
+            if let Some(joined)=joined {
+                rlisteners.send(joined);
+            }
+        });
+        Self {
+            listeners,
+            _source_subscription: source_subscription,
+            _source2_subscription: source2_subscription,
+            getter
+        }
+    }
+}
+
+impl<K:Eq+Hash+Clone+Send+Sync+'static,V:Eq+Hash+Clone+Send+Sync+'static, V2:Eq+Hash+Clone+Send+Sync+'static,
+    SourceGetter: SyncQuerableStreamingMultiMapGetter<K,V>+'static,
+    SourceGetter2: SyncQuerableStreamingMultiMapGetter<K,V2>+'static>
+    SyncQuerableStreamingMultiMap<K,(V, V2)>
+    for SyncJoinQuerableStreamingMultiMap<K,V, V2, SourceGetter, SourceGetter2> {
+    type Getter = SyncJoinQuerableStreamingMultiMapGetter<K,V, V2, SourceGetter, SourceGetter2>;
+    fn getter(&self)->&Arc<Self::Getter> {
+        &self.getter
+    }
+}
+
+impl<K:Eq+Hash+Clone+Send+Sync+'static,V:Eq+Hash+Clone+Send+Sync+'static, V2:Eq+Hash+Clone+Send+Sync+'static,
+    SourceGetter: SyncQuerableStreamingMultiMapGetter<K,V>,
+    SourceGetter2: SyncQuerableStreamingMultiMapGetter<K,V2>>
+    SyncMessageListenersInterface<MultiSetModifyMessage<(K,(V, V2))>>
+    for SyncJoinQuerableStreamingMultiMap<K,V, V2, SourceGetter, SourceGetter2> {
+    fn listeners(&self)->&SyncMessageListeners<MultiSetModifyMessage<(K,(V, V2))>> {
+        &self.listeners
+    }
+}
+
+impl<K:Eq+Hash+Clone+Send+Sync+'static,V:Eq+Hash+Clone+Send+Sync+'static> SyncMultiSetMessageListeners<(K,V)> {
+    pub fn reversed(&self)->Arc<SyncStreamingHashMultiMapWithCount<V,K>> {
+        self.group_by(|k,v| (v,k))
+    }
+
+    pub fn group_by<K2: Eq+Hash+Clone+Send+Sync+'static, V2: Eq+Hash+Clone+Send+Sync+'static>(
+            &self, f:impl Fn(K, V)->(K2, V2)+Send+'static)->
+            Arc<SyncStreamingHashMultiMapWithCount<K2,V2>> {
+        let r=Arc::new(SyncStreamingHashMultiMapWithCount::in_graph(self.graph_lock()));
+        let rclone=r.clone();
+        self.listen(move |message: MultiSetModifyMessage<(K,V)>| rclone.apply(message.map_items(&|(k, v)| f(k, v)))).detach();
+        r
+    }
+}
+
+#[test]
+fn test_sync_join() {
+    let map1 = SyncStreamingHashMultiMapWithCount::new();
+    let map2 = SyncStreamingHashMultiMapWithCount::new();
+    let joined_map = map1.join(&map2);
+    map1.insert("key", "value");
+    map2.insert("key", "value2");
+    assert_eq!(joined_map.get_one(&"key"), Some(("value", "value2")));
+}
+
+#[test]
+fn test_sync_filter_group_by() {
+    let map = SyncStreamingHashMultiMapWithCount::new();
+    let filter_map = map.filter_item(|k, _| k=="key");
+    let reversed = filter_map.reversed();
+    map.insert("key", "value");
+    map.insert("key2", "value2");
+    assert_eq!(filter_map.get_one(&"key"), Some("value"));
+    assert_eq!(filter_map.get_one(&"key2"), None);
+    assert_eq!(reversed.get_one(&"value"), Some("key"));
+    assert_eq!(reversed.get_one(&"value2"), None);
+}
+
+#[test]
+fn test_sync_join_dropped() {
+    let map1 = SyncStreamingHashMultiMapWithCount::new();
+    let map2 = SyncStreamingHashMultiMapWithCount::new();
+    let grouped = {
+        let joined_map = map1.join(&map2);
+        joined_map.group_by(|k, (v, v2)| (k, (v, v2)))
+    };
+    map1.insert("key", "value");
+    map2.insert("key", "value2");
+    assert_eq!(grouped.get_one(&"key"), None);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_sync_multi_threaded_writers() {
+    let tweets = Arc::new(SyncStreamingHashMultiMapWithCount::<u32, u32>::new());
+    let follows = Arc::new(SyncStreamingHashMultiMapWithCount::<u32, u32>::new());
+    let joined = tweets.join(&*follows);
+    let tweets_by_follower = joined.group_by(|_user, (tweet, follower)| (follower, tweet));
+    fn assert_send_sync<T: Send+Sync>(_: &T) {}
+    assert_send_sync(&joined);
+    assert_send_sync(&follows.filter_item(|_, _| true));
+    let mut handles = Vec::new();
+    for user in 0..8 {
+        let tweets = tweets.clone();
+        let follows = follows.clone();
+        handles.push(tokio::spawn(async move {
+            follows.insert(user, 100);
+            tweets.insert(user, user * 10);
+        }));
+    }
+    for handle in handles {
+        handle.await.unwrap();
+    }
+    assert_eq!(tweets_by_follower.get(&100), (0..8).map(|user| user * 10).collect());
+    for user in 0..8 {
+        follows.remove(user, 100);
+    }
+    assert_eq!(tweets_by_follower.get(&100), HashSet::new());
+}
+
+#[test]
+fn test_sync_late_group_by_backfills() {
+    let map1 = SyncStreamingHashMultiMapWithCount::new();
+    let map2 = SyncStreamingHashMultiMapWithCount::new();
+    map1.insert("key", "value");
+    map2.insert("key", "value2");
+    let joined_map = map1.join(&map2);
+    let reversed = joined_map.reversed();
+    assert_eq!(reversed.get_one(&("value", "value2")), Some("key"));
+    map2.remove("key", "value2");
+    assert_eq!(reversed.get_one(&("value", "value2")), None);
+}
+
+#[test]
+fn test_sync_group_by_during_writes() {
+    let map = Arc::new(SyncStreamingHashMultiMapWithCount::new());
+    let writer = {
+        let map = map.clone();
+        std::thread::spawn(move || for i in 0..1000 {
+            map.insert(i, i);
+        })
+    };
+    let reversed = map.reversed();
+    writer.join().unwrap();
+    assert_eq!(reversed.items().len(), 1000);
+    assert_eq!(reversed.get_one(&999), Some(999));
+}
+
+#[test]
+fn test_sync_apply_delta() {
+    let map = SyncStreamingHashMultiMapWithCount::new();
+    let grouped = map.group_by(|k, v| (v, k));
+    map.apply(MultiSetModifyMessage::Delta(("key", "value"), 3));
+    map.remove("key", "value");
+    assert_eq!(grouped.get_one(&"value"), Some("key"));
+    map.apply(MultiSetModifyMessage::Delta(("key", "value"), -5));
+    assert_eq!(grouped.get_one(&"value"), None);
+}
+
+#[test]
+fn test_sync_apply_is_atomic() {
+    let map = SyncStreamingHashMultiMapWithCount::new();
+    let messages = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let mclone = messages.clone();
+    let _subscription = map.listen(move |message| mclone.lock().unwrap().push(message));
+    map.insert("key", "value");
+    map.set("key", "value2");
+    map.apply(MultiSetModifyMessage::Batch(vec![MultiSetModifyMessage::InsertOne(("key", "value3")),
+        MultiSetModifyMessage::RemoveOne(("key", "value3")), MultiSetModifyMessage::InsertOne(("key2", "value"))]));
+    assert_eq!(*messages.lock().unwrap(), vec![MultiSetModifyMessage::InsertOne(("key", "value")),
+        MultiSetModifyMessage::Replace { old: ("key", "value"), new: ("key", "value2") },
+        MultiSetModifyMessage::InsertOne(("key2", "value"))]);
+}
+
+#[test]
+fn test_sync_clear_and_remove_key() {
+    let map = SyncStreamingHashMultiMapWithCount::new();
+    let map2 = SyncStreamingHashMultiMapWithCount::new();
+    map.insert("client", "uid1");
+    map.insert("client2", "uid2");
+    map2.insert("client", "session");
+    let joined_map = map.join(&map2);
+    let grouped = joined_map.group_by(|k, v| (k, v));
+    assert!(map.remove_key(&"client"));
+    assert_eq!(grouped.get(&"client"), HashSet::new());
+    map.insert("client", "uid1");
+    map.retain(|_, v| *v=="uid1");
+    assert_eq!(map.items(), vec![("client", "uid1")]);
+    map.insert("client3", "uid3");
+    map.apply(MultiSetModifyMessage::Clear(vec![("client3", "uid3")]));
+    assert_eq!(map.items(), vec![("client", "uid1")]);
+    map.clear();
+    assert_eq!(map.items(), vec![]);
+    assert_eq!(grouped.items(), vec![]);
+}
+
+#[test]
+fn test_sync_unrelated_graphs_write_in_parallel() {
+    let map1 = Arc::new(SyncStreamingHashMultiMapWithCount::new());
+    let map2 = SyncStreamingHashMultiMapWithCount::new();
+    let (entered_tx, entered) = std::sync::mpsc::channel();
+    let (release, release_rx) = std::sync::mpsc::channel::<()>();
+    let _subscription = map1.listen(move |_| {
+        entered_tx.send(()).unwrap();
+        release_rx.recv().unwrap();
+    });
+    let writer = {
+        let map1 = map1.clone();
+        std::thread::spawn(move || map1.insert("key", "value"))
+    };
+    entered.recv().unwrap();
+    // The writer of map1 is still propagating, map2 is in another graph.
+    map2.insert("key", "value2");
+    release.send(()).unwrap();
+    writer.join().unwrap();
+    assert_eq!(map2.get_one(&"key"), Some("value2"));
+    assert_eq!(map1.get_one(&"key"), Some("value"));
+}
+
+#[test]
+fn test_sync_join_multiplies_weights() {
+    let map = SyncStreamingHashMultiMapWithCount::new();
+    let map2 = SyncStreamingHashMultiMapWithCount::new();
+    map2.insert("key", "value2");
+    map2.insert("key", "value2");
+    let joined_map = map.join(&map2);
+    let grouped = joined_map.group_by(|k, v| (k, v));
+    map.add("key", "value", 3);
+    assert_eq!(grouped.weighted_items(), vec![(("key", ("value", "value2")), 6)]);
+    map2.remove("key", "value2");
+    assert_eq!(grouped.weighted_items(), vec![(("key", ("value", "value2")), 3)]);
+    map.clear();
+    assert_eq!(grouped.weighted_items(), vec![]);
+}
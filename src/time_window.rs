@@ -0,0 +1,180 @@
+use std::{cell::{Cell, RefCell}, collections::BTreeSet, hash::Hash, rc::Rc};
+
+use chrono::{Duration, NaiveDateTime, Utc};
+
+use crate::{message_listeners::{MessageListeners, MessageListenersInterface}, multi_set::MultiSetModifyMessage,
+    queryable_streaming_multi_map::StreamingHashMultiMapWithCount};
+#[cfg(test)]
+use crate::{multi_set::MultiSetMessageListeners, queryable_streaming_multi_map::QuerableStreamingMultiMap};
+
+/// The current time for the time based operators.
+///
+/// Operators close their windows when the clock moves: use `tick` to follow the system time
+///   (for example from a timer), or `set` / `advance` to drive time by hand in tests.
+pub struct Clock<'listener> {
+    now: Cell<NaiveDateTime>,
+    listeners: MessageListeners<'listener, NaiveDateTime>
+}
+
+impl<'listener> MessageListenersInterface<'listener, NaiveDateTime> for Clock<'listener> {
+    fn listeners(&self)->&MessageListeners<'listener, NaiveDateTime> {
+        &self.listeners
+    }
+}
+
+impl<'listener> Default for Clock<'listener> {
+    fn default()->Self {
+        Self::new()
+    }
+}
+
+impl<'listener> Clock<'listener> {
+    /// A clock that starts at the system time.
+    pub fn new()->Self {
+        Self::manual(Utc::now().naive_utc())
+    }
+    /// A clock that starts at `now` and only moves when told to.
+    pub fn manual(now: NaiveDateTime)->Self {
+        Self { now: Cell::new(now), listeners: MessageListeners::new() }
+    }
+    pub fn now(&self)->NaiveDateTime {
+        self.now.get()
+    }
+    /// Moves the clock to `now`, clocks never go backwards.
+    pub fn set(&self, now: NaiveDateTime) {
+        if now<=self.now.get() {
+            return;
+        }
+        self.now.set(now);
+        self.listeners.send(now);
+    }
+    pub fn advance(&self, duration: Duration) {
+        self.set(self.now.get()+duration);
+    }
+    /// Moves the clock to the system time.
+    pub fn tick(&self) {
+        self.set(Utc::now().naive_utc());
+    }
+    /// Moves the clock to the system time every `period` using a tokio timer, for example to
+    ///   expire the pairs of maps created by `StreamingHashMultiMapWithCount::with_clock`.  The
+    ///   future never completes, drop it to stop ticking.
+    pub async fn tick_periodically(&self, period: std::time::Duration) {
+        let mut interval=tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            self.tick();
+        }
+    }
+}
+
+/// The starts of the windows of length `size`, starting every `slide`, that contain `time`.
+///   Windows are aligned to the unix epoch.
+fn window_starts(time: NaiveDateTime, size: Duration, slide: Duration)->Vec<NaiveDateTime> {
+    let (t, size, slide)=(time.timestamp_millis(), size.num_milliseconds(), slide.num_milliseconds());
+    let mut start=t.div_euclid(slide)*slide;
+    let mut r=Vec::new();
+    while start+size>t {
+        // Not from_timestamp_millis: older chrono versions build unequal values for times before 1970.
+        r.push(NaiveDateTime::from_timestamp_opt(start.div_euclid(1000), (start.rem_euclid(1000)*1_000_000) as u32).unwrap());
+        start-=slide;
+    }
+    r
+}
+
+/// Assigns the items of `source` to windows by the time returned by `time`, see
+///   `MultiSetMessageListeners::sliding_window`.
+pub fn sliding_window<'listener, T: Eq+Hash+Clone+'static>(source: &impl MessageListenersInterface<'listener, MultiSetModifyMessage<T>>,
+        existing: Vec<(T, u64)>, clock: &Clock<'listener>, time: impl Fn(&T)->NaiveDateTime + 'listener, size: Duration, slide: Duration)->
+        Rc<StreamingHashMultiMapWithCount<'listener, NaiveDateTime, T>> {
+    assert!(size>Duration::zero() && slide>Duration::zero(), "window size and slide must be positive");
+    let r: Rc<StreamingHashMultiMapWithCount<'listener, NaiveDateTime, T>>=Rc::new(StreamingHashMultiMapWithCount::new());
+    r.listeners().depends_on(source.listeners());
+    r.listeners().depends_on(clock.listeners());
+    let open: Rc<RefCell<BTreeSet<NaiveDateTime>>>=Rc::new(RefCell::new(BTreeSet::new()));
+    let now=Rc::new(Cell::new(clock.now()));
+    // The windows of an item that are still open, items of closed windows are dropped.
+    let assign={
+        let (open, now)=(open.clone(), now.clone());
+        move |item: T| {
+            let starts: Vec<_>=window_starts(time(&item), size, slide).into_iter()
+                .filter(|start| *start+size>now.get()).collect();
+            open.borrow_mut().extend(starts.iter().cloned());
+            starts.into_iter().map(|start| (start, item.clone())).collect::<Vec<_>>()
+        }
+    };
+    r.transaction(|tx| for (item, count) in existing {
+        for (start, item) in assign(item) {
+            tx.add(start, item, count as i64);
+        }
+    });
+    let weak=Rc::downgrade(&r);
+    r.listeners().hold(source.listen(move |message| {
+        if let Some(r)=weak.upgrade() {
+            if let Some(message)=message.flat_map_items(&assign) {
+                r.apply(message);
+            }
+        }
+    }));
+    let weak=Rc::downgrade(&r);
+    r.listeners().hold(clock.listen(move |time| {
+        let Some(r)=weak.upgrade() else {
+            return;
+        };
+        now.set(time);
+        let closed: Vec<_>={
+            let mut open=open.borrow_mut();
+            let still_open=match time.checked_sub_signed(size) {
+                Some(first_open)=>open.split_off(&(first_open+Duration::milliseconds(1))),
+                None=>return
+            };
+            std::mem::replace(&mut *open, still_open).into_iter().collect()
+        };
+        r.transaction(|tx| for start in closed {
+            tx.remove_key(&start);
+        });
+    }));
+    r
+}
+
+#[cfg(test)]
+fn at(seconds: i64)->NaiveDateTime {
+    NaiveDateTime::from_timestamp_opt(seconds, 0).unwrap()
+}
+
+#[test]
+fn test_tumbling_window() {
+    let clock = Clock::manual(at(0));
+    let events: MultiSetMessageListeners<(&str, i64)> = MultiSetMessageListeners::new();
+    let windows = events.tumbling_window(&clock, |(_, t)| at(*t), Duration::seconds(10));
+    let counts = windows.count_by_key();
+    events.send(MultiSetModifyMessage::InsertOne(("a", 1)));
+    events.send(MultiSetModifyMessage::InsertOne(("b", 9)));
+    events.send(MultiSetModifyMessage::InsertOne(("c", 12)));
+    assert_eq!(counts.get_one(&at(0)), Some(2));
+    assert_eq!(counts.get_one(&at(10)), Some(1));
+    clock.advance(Duration::seconds(10));
+    assert_eq!(counts.get_one(&at(0)), None);
+    assert_eq!(windows.get_one(&at(10)), Some(("c", 12)));
+    // Late items of closed windows are dropped.
+    events.send(MultiSetModifyMessage::InsertOne(("d", 5)));
+    assert_eq!(windows.get(&at(0)).len(), 0);
+    events.send(MultiSetModifyMessage::RemoveOne(("c", 12)));
+    assert_eq!(counts.get_one(&at(10)), None);
+}
+
+#[test]
+fn test_sliding_window() {
+    let clock = Clock::manual(at(0));
+    let logins = StreamingHashMultiMapWithCount::new();
+    logins.insert("alice", 3);
+    let windows = logins.sliding_window(&clock, |(_, t)| at(*t), Duration::seconds(10), Duration::seconds(5));
+    logins.insert("bob", 7);
+    assert_eq!(windows.get(&at(-5)).len(), 1);
+    assert_eq!(windows.get(&at(0)).len(), 2);
+    assert_eq!(windows.get_one(&at(5)), Some(("bob", 7)));
+    clock.set(at(5));
+    assert_eq!(windows.get(&at(-5)).len(), 0);
+    assert_eq!(windows.get(&at(0)).len(), 2);
+    clock.set(at(15));
+    assert_eq!(windows.items(), vec![]);
+}
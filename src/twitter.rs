@@ -9,7 +9,6 @@ pub struct ClientId {
 }
 
 /// A function decorator that puts its all arguments into a message and sends it to a message listener.
-
 macro_rules! add_to_web_socket {
     ($ws:expr, $($arg:expr),*) => {
         {
@@ -22,6 +21,11 @@ macro_rules! add_to_web_socket {
 
 pub struct WebSocketByClient {
 }
+impl Default for WebSocketByClient {
+    fn default()->Self {
+        Self::new()
+    }
+}
 impl WebSocketByClient {
     pub fn new()->Self {
         Self {}
@@ -29,7 +33,7 @@ impl WebSocketByClient {
     pub fn client_collection<'a, M: Debug+Clone+'static>(&self, ml: &impl MessageListenersInterface<'a, M>) {
         ml.listen(|m| {
             println!("client_collection: {:?}", m);
-        });
+        }).detach();
     }
     pub fn client_fn<T>(&self, _f: impl FnMut(ClientId, T)) {
         unimplemented!()
@@ -67,6 +71,8 @@ impl<'a, M: Debug+Clone+'static, I: MessageListenersInterface<'a, M>>  MessageLi
 // - The most important traits are QuerableStreamingMultiMap (that can be joined into one on the same key)
 //     and StreamingMultiSet (that can't be joined, but can be mapped and grouped (for a Key,Value tuple))
 
+const TIMELINE_LENGTH: usize = 50;
+
 pub fn test_twitter() {
     let tweets: StreamingHashMultiMapWithCount<Uuid, (NaiveDateTime, String)>=StreamingHashMultiMapWithCount::new();
     let follows: StreamingHashMultiMapWithCount<Uuid, Uuid>=StreamingHashMultiMapWithCount::new();
@@ -88,6 +94,8 @@ pub fn test_twitter() {
              ((client_id, logged_in_user_id),
                 (time, string))|
             (client_id, (logged_in_user_id, followed_user_id, time, string)));
+    // Clients only render the latest tweets, older ones are removed as new ones arrive.
+    let timeline_by_client = seen_by_client.top_k_by_key(TIMELINE_LENGTH, |(_, _, time, _)| *time);
     let mut ws = WebSocketByClient::new();
     let follow=|client_id, uuid| {
         let user_id = uid_by_client.get_one(&client_id);
@@ -103,7 +111,7 @@ pub fn test_twitter() {
             tweets.insert(user_id, (Utc::now().naive_utc(), s));
         }
     };
-    renderer(&mut ws, &*seen_by_client, &*followed_by_client, follow, set_uuid, create_tweet);
+    renderer(&mut ws, &*timeline_by_client, &*followed_by_client, follow, set_uuid, create_tweet);
 }
 
 